@@ -1,6 +1,6 @@
 use yew::prelude::*;
 use yew::{html, html_impl};
-use crate::lisp::{Context, eval_in_context};
+use lesp::{Error, Interpreter, Value};
 
 pub struct RootModel {
     inputs: Vec<(String, String)>
@@ -10,20 +10,20 @@ pub enum RootMsg {
     Eval(String), Noop
 }
 
-impl Component<Context> for RootModel {
+impl Component<Interpreter> for RootModel {
     type Message = RootMsg;
     type Properties = ();
 
-    fn create(_: Self::Properties, mut context: &mut Env<Context, Self>) -> Self {
+    fn create(_: Self::Properties, lisp: &mut Env<Interpreter, Self>) -> Self {
         let example = String::from("(defn identity (a) a)");
-        RootModel { inputs: vec![(example.clone(), format!("{:?}", eval_in_context(&example, &mut context)))] }
+        RootModel { inputs: vec![(example.clone(), show(lisp.eval_source(&example, "<example>")))] }
     }
 
-    fn update(&mut self, msg: Self::Message, mut context: &mut Env<Context, Self>) -> ShouldRender {
+    fn update(&mut self, msg: Self::Message, lisp: &mut Env<Interpreter, Self>) -> ShouldRender {
         match msg {
             RootMsg::Noop => false,
             RootMsg::Eval(form) => {
-                let result = format!("{:?}", eval_in_context(&form, &mut context));
+                let result = show(lisp.eval_source(&form, "<input>"));
                 self.inputs.push((form, result));
                 true
             }
@@ -31,14 +31,21 @@ impl Component<Context> for RootModel {
     }
 }
 
-fn io(io: (String, String)) -> Html<Context, RootModel> {
+fn show(result: Result<Value, Error>) -> String {
+    match result {
+        Ok(value) => format!("{}", value),
+        Err(error) => format!("!! {}", error)
+    }
+}
+
+fn io(io: (String, String)) -> Html<Interpreter, RootModel> {
     html! {
         <div>{io.0} {" => "} <kbd>{io.1}</kbd></div>
     }
 }
 
-impl Renderable<Context, RootModel> for RootModel {
-    fn view(&self) -> Html<Context, Self> {
+impl Renderable<Interpreter, RootModel> for RootModel {
+    fn view(&self) -> Html<Interpreter, Self> {
         html! {
             <div class={"jumbotron"},><div class={"container"},>
                 <h2 class={"text-center"},>{"Lesp"}</h2>
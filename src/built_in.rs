@@ -1,106 +1,213 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use crate::lisp::{Type, Context, FunctionType, Function, NativeFunction};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::lisp;
+use crate::namespace;
+use crate::parser;
+use crate::pretty;
+use crate::lisp::{Type, Context, FunctionType, Function, Native, Arity, EvalError};
+
+fn arg(args: &[Type], i: usize) -> Result<&Type, EvalError> {
+    args.get(i).ok_or_else(|| EvalError::runtime(format!("Missing argument #{}", i + 1)))
+}
 
-fn add(mut context: &mut Context, args:&[Type]) -> Type {
-    Type::Number(args.into_iter().map(|x| {
-        if let Type::Number(n) = x.eval(&mut context) {
-            n
-        } else { panic!()}
-    }).fold(0, |acc, x| acc + x))
+fn eval_arg(context: &mut Context, args: &[Type], i: usize) -> Result<Type, EvalError> {
+    arg(args, i)?.eval(context)
 }
 
-fn is_list(mut context: &mut Context, args:&[Type]) -> Type {
-    if let Type::List(_l) = args.get(0).unwrap().eval(&mut context) {
-        Type::Bool(true)
+fn number(value: &Type) -> Result<i64, EvalError> {
+    if let Type::Number(n) = value {
+        Ok(*n)
     } else {
-        Type::Bool(false)
+        Err(EvalError::runtime(format!("Number expected -> {}", value)))
     }
 }
 
-fn prn(mut context: &mut Context, args:&[Type]) -> Type {
-    let arg = args.get(0).unwrap();
-    let result = arg.eval(&mut context);
-    println!("{:?}", &result);
-    result
+fn symbol(value: &Type) -> Result<&String, EvalError> {
+    if let Type::Symbol(s) = value {
+        Ok(s)
+    } else {
+        Err(EvalError::runtime(format!("Symbol expected -> {}", value)))
+    }
 }
 
-fn dbg(mut context: &mut Context, args:&[Type]) -> Type {
-    let arg = args.get(0).unwrap();
-    let result = arg.eval(&mut context);
-    println!("{:?} -> {:?}", &arg, &result);
-    result
+// nil is accepted everywhere list is expected
+fn elements(value: &Type) -> Result<&[Type], EvalError> {
+    match value {
+        Type::List(elems) => Ok(elems),
+        Type::Nil => Ok(&[]),
+        _ => Err(EvalError::runtime(format!("List expected -> {}", value)))
+    }
 }
 
-fn sub(mut context: &mut Context, args:&[Type]) -> Type {
-    let first = if let Type::Number(n) = args.get(0).unwrap().eval(&mut context) {
-        n
-    } else {panic!()};
-    Type::Number(args[1..].into_iter().map(|x| {
-        if let Type::Number(n) = x.eval(&mut context) {
-            n
-        } else { panic!()}
-    }).fold(first, |acc, x| {
-        acc - x
-    }))
+fn eval_body(context: &mut Context, forms: &[Type]) -> Result<Type, EvalError> {
+    let mut result = Type::Nil;
+    for form in forms {
+        result = form.eval(context)?;
+    }
+    Ok(result)
 }
 
-fn mult(mut context: &mut Context, args:&[Type]) -> Type {
-    Type::Number(args.into_iter().map(|x| {
-        if let Type::Number(n) = x.eval(&mut context) {
-            n
-        } else { panic!()}
-    }).fold(1, |acc, x| acc * x))
+fn overflow() -> EvalError {
+    EvalError::runtime("Arithmetic overflow")
 }
 
-fn car(mut context: &mut Context, args:&[Type]) -> Type {
-    if args.len() == 0 {
-        return Type::List(vec![]);
+fn add(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let mut acc: i64 = 0;
+    for x in args {
+        acc = acc.checked_add(number(x)?).ok_or_else(overflow)?;
     }
-    if let Type::List(elems) = args.get(0).unwrap().eval(&mut context) {
-        if elems.len() == 0 {
-            return Type::List(vec![]);
-        }
-        elems.get(0).unwrap().clone()
-    } else { panic!() }
+    Ok(Type::Number(acc))
 }
 
-fn cdr(mut context: &mut Context, args:&[Type]) -> Type {
-    if args.len() == 0 {
-        return Type::List(vec![]);
+fn is_list(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    if let Type::List(_l) = arg(args, 0)? {
+        Ok(Type::Bool(true))
+    } else {
+        Ok(Type::Bool(false))
     }
-    if let Type::List(elems) = args.get(0).unwrap().eval(&mut context) {
-        if elems.len() == 0 {
-            return Type::List(vec![]);
-        }
-        Type::List(elems[1..].to_vec())
-    } else { panic!() }
 }
 
-fn cons(mut context: &mut Context, args:&[Type]) -> Type {
-    let first = args.get(0).unwrap().eval(&mut context);
-    if let Type::List(elems) = args.get(1).unwrap().eval(&mut context) {
-        let mut new_list = vec![first];
-        new_list.extend(elems);
-        Type::List(new_list)
-    } else { panic!() }
+fn is_nil(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    Ok(Type::Bool(*arg(args, 0)? == Type::Nil))
+}
+
+fn prn(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let result = arg(args, 0)?.clone();
+    println!("{}", &result);
+    Ok(result)
+}
+
+/**
+(pprint (genlist 100))
+Prints the value laid out within 80 columns
+*/
+fn pprint(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let result = arg(args, 0)?.clone();
+    println!("{}", pretty::pretty(&result, pretty::WIDTH));
+    Ok(result)
+}
+
+/**
+(pr-str "a" '(b 1))
+-> "\"a\" (b 1)"
+*/
+fn pr_str(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let printed: Vec<String> = args.iter().map(Type::to_string).collect();
+    Ok(Type::Str(printed.join(" ")))
+}
+
+/**
+(read-string "(+ 1 2)")
+-> (+ 1 2)
+Reads the first form, nil when there is none
+*/
+fn read_string(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let input = match arg(args, 0)? {
+        Type::Str(input) => input,
+        other => return Err(EvalError::runtime(format!("String expected -> {}", other)))
+    };
+    let (forms, _) = parser::read(input).map_err(|e| EvalError::runtime(e.to_string()))?;
+    Ok(forms.into_iter().next().unwrap_or(Type::Nil))
+}
+
+fn dbg(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let arg = arg(args, 0)?;
+    let result = arg.eval(context)?;
+    println!("{} -> {}", &arg, &result);
+    Ok(result)
+}
+
+fn sub(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let mut acc = number(arg(args, 0)?)?;
+    for x in &args[1..] {
+        acc = acc.checked_sub(number(x)?).ok_or_else(overflow)?;
+    }
+    Ok(Type::Number(acc))
+}
+
+fn mult(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let mut acc: i64 = 1;
+    for x in args {
+        acc = acc.checked_mul(number(x)?).ok_or_else(overflow)?;
+    }
+    Ok(Type::Number(acc))
+}
+
+/**
+(car (list 1 2 3))
+-> 1
+(car (list))
+-> nil
+*/
+fn car(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    if args.is_empty() {
+        return Ok(Type::Nil);
+    }
+    let elems = elements(arg(args, 0)?)?;
+    Ok(elems.first().cloned().unwrap_or(Type::Nil))
+}
+
+/**
+(cdr (list 1 2 3))
+-> (2 3)
+(cdr (list))
+-> ()
+*/
+fn cdr(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    if args.is_empty() {
+        return Ok(Type::List(vec![]));
+    }
+    let elems = elements(arg(args, 0)?)?;
+    if elems.is_empty() {
+        return Ok(Type::List(vec![]));
+    }
+    Ok(Type::List(elems[1..].to_vec()))
 }
 
-fn apply(mut context: &mut Context, args:&[Type]) -> Type {
-    if let Type::List(elems) = args.get(1).unwrap().eval(&mut context) {
-        if let Type::Function(f) = args.get(0).unwrap().eval(&mut context) {
-            f.eval(&mut context, &elems[..])
-        } else { panic!() }
-    } else { panic!() }
+/**
+(get (list 1 2 3) 1)
+-> 2
+(get (list 1 2 3) 10)
+-> nil
+*/
+fn get(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let elems = elements(arg(args, 0)?)?;
+    let index = number(arg(args, 1)?)?;
+    Ok(usize::try_from(index).ok().and_then(|i| elems.get(i)).cloned().unwrap_or(Type::Nil))
+}
+
+fn cons(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let first = arg(args, 0)?;
+    let elems = elements(arg(args, 1)?)?;
+    let mut new_list = Vec::with_capacity(elems.len() + 1);
+    new_list.push(first.clone());
+    new_list.extend_from_slice(elems);
+    Ok(Type::List(new_list))
+}
+
+/**
+(apply + (list 1 2 3))
+-> 6
+Elements are passed as they are, without evaluating them again
+*/
+fn apply(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let elems = elements(arg(args, 1)?)?.to_vec();
+    match arg(args, 0)? {
+        Type::Function(f) => f.call(context, elems),
+        other => Err(EvalError::runtime(format!("Function expected -> {}", other)))
+    }
 }
 
-fn push(mut context: &mut Context, args:&[Type]) -> Type {
-    let first = args.get(0).unwrap().eval(&mut context);
-    if let Type::List(elems) = args.get(1).unwrap().eval(&mut context) {
-        let mut new_list = elems.clone();
-        new_list.push(first);
-        Type::List(new_list)
-    } else { panic!() }
+fn push(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let first = arg(args, 0)?;
+    let mut new_list = elements(arg(args, 1)?)?.to_vec();
+    new_list.push(first.clone());
+    Ok(Type::List(new_list))
 }
 
 /**
@@ -109,8 +216,119 @@ fn push(mut context: &mut Context, args:&[Type]) -> Type {
  * (quote 1)
  * -> 1
  */
-fn quote(_context: &mut Context, args:&[Type]) -> Type {
-    args.get(0).unwrap().clone()
+fn quote(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    arg(args, 0).cloned()
+}
+
+/**
+ * (def b 2)
+ * (quasiquote (a (unquote b) (unquote-splicing (list 3 4))))
+ * `(a ,b ,@(list 3 4))
+ * -> (a 2 3 4)
+ */
+fn quasiquote(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    quasiquote_form(context, arg(args, 0)?, 1, &mut HashMap::new())
+}
+
+// `gensyms` keeps auto-gensym names (`x#`) consistent within a single expansion
+fn quasiquote_form(context: &mut Context, form: &Type, depth: usize, gensyms: &mut HashMap<String, String>) -> Result<Type, EvalError> {
+    let elems = match form {
+        Type::List(elems) => elems,
+        Type::Symbol(s) if depth == 1 && s.len() > 1 && s.ends_with('#') => {
+            // vararg names keep their `...` suffix and share the gensym with their plain form
+            let name = &s[..s.len() - 1];
+            let (base, suffix) = match name.strip_suffix("...") {
+                Some(base) => (base, "..."),
+                None => (name, "")
+            };
+            let generated = gensyms.entry(String::from(base)).or_insert_with(|| gensym(base));
+            return Ok(Type::Symbol(format!("{}{}", generated, suffix)));
+        },
+        _ => return Ok(form.clone())
+    };
+    match elems.first() {
+        Some(Type::Symbol(s)) if s == "unquote" => {
+            if depth == 1 {
+                return eval_arg(context, elems, 1);
+            }
+            return Ok(Type::List(vec![elems[0].clone(), quasiquote_form(context, arg(elems, 1)?, depth - 1, gensyms)?]));
+        },
+        Some(Type::Symbol(s)) if s == "quasiquote" => {
+            return Ok(Type::List(vec![elems[0].clone(), quasiquote_form(context, arg(elems, 1)?, depth + 1, gensyms)?]));
+        },
+        // splicing into a list is handled below, there is nothing to splice into here
+        Some(Type::Symbol(s)) if s == "unquote-splicing" && depth == 1 => {
+            return Err(EvalError::runtime("unquote-splicing outside of a list"));
+        },
+        _ => ()
+    }
+    let mut result = vec![];
+    for elem in elems {
+        match elem {
+            Type::List(inner) if depth == 1 && inner.first() == Some(&Type::Symbol(String::from("unquote-splicing"))) => {
+                match eval_arg(context, inner, 1)? {
+                    Type::List(spliced) => result.extend(spliced),
+                    Type::Nil => (),
+                    other => return Err(EvalError::runtime(format!("unquote-splicing expects a list -> {}", other)))
+                }
+            },
+            Type::List(inner) if inner.first() == Some(&Type::Symbol(String::from("unquote-splicing"))) => {
+                result.push(Type::List(vec![inner[0].clone(), quasiquote_form(context, arg(inner, 1)?, depth - 1, gensyms)?]));
+            },
+            _ => result.push(quasiquote_form(context, elem, depth, gensyms)?)
+        }
+    }
+    Ok(Type::List(result))
+}
+
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn gensym(prefix: &str) -> String {
+    format!("{}__{}", prefix, GENSYM_COUNTER.fetch_add(1, Ordering::SeqCst))
+}
+
+/**
+(gensym)
+-> G__12
+(gensym (quote tmp))
+-> tmp__13
+*/
+fn gensym_native(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let prefix = if args.is_empty() {
+        String::from("G")
+    } else {
+        symbol(arg(args, 0)?)?.clone()
+    };
+    Ok(Type::Symbol(gensym(&prefix)))
+}
+
+fn unquote(_context: &mut Context, _args:&[Type]) -> Result<Type, EvalError> {
+    Err(EvalError::runtime("unquote outside of quasiquote"))
+}
+
+/**
+(macroexpand-1 '(when a b))
+-> (if a (do b) nil)
+*/
+fn macroexpand_1_native(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let form = arg(args, 0)?.clone();
+    Ok(lisp::macroexpand_1(&form, context)?.unwrap_or(form))
+}
+
+/**
+(macroexpand '(defn f (a) a))
+-> (def f (fn f (a) a))
+*/
+fn macroexpand_native(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    lisp::macroexpand(arg(args, 0)?, context)
+}
+
+/**
+(macroexpand-all '(defn f (a) (when a 1)))
+-> (def f (fn f (a) (if a (let () 1) nil)))
+*/
+fn macroexpand_all_native(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    lisp::macroexpand_all(arg(args, 0)?, context)
 }
 
 /**
@@ -119,21 +337,49 @@ fn quote(_context: &mut Context, args:&[Type]) -> Type {
  * (list 1 (+ 0 1))
  * -> (1 2)
  */
-fn list(mut context: &mut Context, args:&[Type]) -> Type {
-    let elems = args.into_iter().map(|x| x.eval(&mut context)).collect();
-    Type::List(elems)
+fn list(_context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    Ok(Type::List(args.to_vec()))
 }
 
 /**
 (def a (+ 1 2 ))
 */
-fn def_special(mut context: &mut Context, args:&[Type]) -> Type {
-    let name = if let Type::Symbol(s) = args.get(0).unwrap() {
-        s
-    } else { panic!()};
-    let value = args.get(1).unwrap().eval(&mut context);
-    context.insert(name.clone(), value.clone());
-    value
+fn def_special(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    def_generic(context, args, false)
+}
+
+/**
+(def- helper 1)
+Same as `def`, but other namespaces can't refer to it
+*/
+fn def_private(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    def_generic(context, args, true)
+}
+
+fn def_generic(context: &mut Context, args:&[Type], private: bool) -> Result<Type, EvalError> {
+    let name = symbol(arg(args, 0)?)?;
+    let value = eval_arg(context, args, 1)?;
+    namespace::define(context, name, value.clone(), private);
+    Ok(value)
+}
+
+/**
+(ns geometry)
+Following definitions go to `geometry`, a namespace started by a file ends with it
+*/
+fn ns_special(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let name = symbol(arg(args, 0)?)?.clone();
+    namespace::switch(context, &name);
+    Ok(Type::Symbol(name))
+}
+
+/**
+(in-ns 'user)
+*/
+fn in_ns(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let name = symbol(arg(args, 0)?)?.clone();
+    namespace::switch(context, &name);
+    Ok(Type::Symbol(name))
 }
 
 /**
@@ -141,49 +387,41 @@ fn def_special(mut context: &mut Context, args:&[Type]) -> Type {
       (b (* 1 2)))
   (+ a b))
 */
-fn let_special(context: &mut Context, args:&[Type]) -> Type {
-    let bindings = args.get(0).unwrap();
+fn let_special(context: &mut Context, args:&[Type]) -> Result<Type, EvalError> {
+    let bindings = arg(args, 0)?;
     let mut new_context = context.clone();
     if let Type::List(elems) = bindings {
         for x in elems {
             if let Type::List(binding) = x {
-                let name = if let Type::Symbol(n) = binding.get(0).unwrap() {
-                    n
-                } else { panic!()};
-                let value= binding.get(1).unwrap().eval(&mut new_context);
+                let name = symbol(arg(binding, 0)?)?;
+                let value = eval_arg(&mut new_context, binding, 1)?;
                 new_context.insert(name.clone(), value);
             }
         }
-    } else { panic!()}
-    let len = args.len() - 1;
-    for form in &args[1..len] {
-        form.eval(&mut new_context);
+    } else {
+        return Err(EvalError::runtime(format!("List of bindings expected -> {}", bindings)));
     }
-    args.get(args.len() - 1).unwrap().eval(&mut new_context)
+    eval_body(&mut new_context, &args[1..])
 }
 
-fn fn_generic(context: &mut Context, args:& [Type], is_macro: bool) -> Type {
-    let name = if let Type::Symbol(name) = args.get(0).unwrap() {
-        name.clone()
-    } else { panic!()};
-    let argument_bindings = if let Type::List(names) = args.get(1).unwrap() {
-        names.clone()
-    } else { panic!() };
+fn fn_generic(context: &mut Context, args:& [Type], is_macro: bool) -> Result<Type, EvalError> {
+    let name = symbol(arg(args, 0)?)?.clone();
+    let argument_bindings = match arg(args, 1)? {
+        Type::List(names) => names.clone(),
+        other => return Err(EvalError::runtime(format!("List of arguments expected -> {}", other)))
+    };
     let body = args[2..].to_vec();
     let arglen = argument_bindings.len();
 
-    let is_vararg = arglen > 0 && if let Type::Symbol(name) = argument_bindings.get(arglen - 1).unwrap() {
-        name.ends_with("...")
-    } else {
-        panic!()
-    };
+    for binding in &argument_bindings {
+        symbol(binding)?;
+    }
+    let is_vararg = arglen > 0 && symbol(&argument_bindings[arglen - 1])?.ends_with("...");
 
     let vararg = if is_vararg {
-        let vararg_name = if let Type::Symbol(name) = argument_bindings.get(arglen - 1).unwrap() {
-            let len = name.len() - 3; // drop ...
-            name[0..len].to_string()
-        } else { panic!() };
-        Some(Type::Symbol(vararg_name))
+        let name = symbol(&argument_bindings[arglen - 1])?;
+        let len = name.len() - 3; // drop ...
+        Some(Type::Symbol(name[0..len].to_string()))
     } else {
         None
     };
@@ -195,130 +433,507 @@ fn fn_generic(context: &mut Context, args:& [Type], is_macro: bool) -> Type {
         argument_bindings
     };
 
-    Type::Function(FunctionType::UserDefined(Rc::new(Function {
+    Ok(Type::Function(FunctionType::UserDefined(Rc::new(Function {
         context: context.clone(),
-        name: name,
+        name,
         args: argument_bindings,
-        body: body,
-        is_macro: is_macro,
-        vararg: vararg
-    })))
+        body,
+        is_macro,
+        vararg
+    }))))
 }
 
 /**
 (fn name (a b c)
     (+ a b c))
 */
-fn fn_special(mut context: &mut Context, args:& [Type]) -> Type {
-    fn_generic(&mut context, &args, false)
+fn fn_special(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    fn_generic(context, args, false)
+}
+
+fn macro_scpecial(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    fn_generic(context, args, true)
+}
+
+/**
+Same as `macro`, but bindings introduced by `let` and `fn` inside its
+quasiquote templates are renamed on every expansion, so they can't capture user symbols.
+(hmacro with-ten (body)
+    `(let ((ten 10)) (+ ten ,body)))
+*/
+fn hmacro_special(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let mut args = args.to_vec();
+    for form in args.iter_mut().skip(2) {
+        *form = mark_introduced_bindings(form);
+    }
+    fn_generic(context, &args, true)
+}
+
+// rewrites introduced bindings inside quasiquote templates to auto-gensyms: `tmp` -> `tmp#`
+fn mark_introduced_bindings(form: &Type) -> Type {
+    match form {
+        Type::List(elems) if elems.len() > 1 && elems[0] == Type::Symbol(String::from("quasiquote")) => {
+            Type::List(vec![elems[0].clone(), rename_bindings(&elems[1], &HashSet::new())])
+        },
+        Type::List(elems) => Type::List(elems.iter().map(mark_introduced_bindings).collect()),
+        Type::Located(inner, location) => Type::Located(Box::new(mark_introduced_bindings(inner)), location.clone()),
+        _ => form.clone()
+    }
+}
+
+fn is_template_escape(elems: &[Type]) -> bool {
+    match elems.first() {
+        Some(Type::Symbol(s)) => s == "unquote" || s == "unquote-splicing" || s == "quasiquote",
+        _ => false
+    }
 }
 
-fn macro_scpecial(mut context: &mut Context, args:& [Type]) -> Type {
-    fn_generic(&mut context, &args, true)
+// adds the name bound by `binding`, vararg `rest...` is referred to as `rest` in the body
+fn bind(scope: &mut HashSet<String>, binding: &Type) {
+    if let Type::Symbol(name) = binding {
+        if let Some(plain) = name.strip_suffix("...") {
+            scope.insert(String::from(plain));
+        }
+        scope.insert(name.clone());
+    }
+}
+
+// renames symbols bound by `let` and `fn` forms where those bindings are visible,
+// `scope` holds the names bound by the template around `form`
+fn rename_bindings(form: &Type, scope: &HashSet<String>) -> Type {
+    let elems = match form {
+        Type::Symbol(s) if scope.contains(s) => return Type::Symbol(format!("{}#", s)),
+        Type::Located(inner, location) => return Type::Located(Box::new(rename_bindings(inner, scope)), location.clone()),
+        Type::List(elems) if !is_template_escape(elems) => elems,
+        _ => return form.clone()
+    };
+    let mut scope = scope.clone();
+    let mut result = vec![];
+    let body = match (elems.first(), elems.get(1)) {
+        (Some(Type::Symbol(s)), Some(Type::List(pairs))) if s == "let" => {
+            // an init form sees the bindings before it, not its own
+            let pairs = pairs.iter().map(|pair| match pair {
+                Type::List(pair) if !pair.is_empty() => {
+                    let init: Vec<Type> = pair[1..].iter().map(|e| rename_bindings(e, &scope)).collect();
+                    bind(&mut scope, &pair[0]);
+                    Type::List(std::iter::once(rename_bindings(&pair[0], &scope)).chain(init).collect())
+                },
+                other => rename_bindings(other, &scope)
+            }).collect();
+            result.push(elems[0].clone());
+            result.push(Type::List(pairs));
+            &elems[2..]
+        },
+        (Some(Type::Symbol(s)), Some(name)) if s == "fn" || s == "macro" => {
+            bind(&mut scope, name);
+            if let Some(Type::List(params)) = elems.get(2) {
+                params.iter().for_each(|p| bind(&mut scope, p));
+            }
+            result.push(elems[0].clone());
+            &elems[1..]
+        },
+        _ => &elems[..]
+    };
+    result.extend(body.iter().map(|e| rename_bindings(e, &scope)));
+    Type::List(result)
 }
 
 /**
 (if form
     then
     else)
+The value is nil when form is false and there is no else
 */
-fn if_special(mut context: &mut Context, args:& [Type]) -> Type {
-    let test = args.get(0).unwrap().eval(&mut context);
-    if let Type::Bool(t) = test {
-        if t {
-            args.get(1).unwrap().eval(&mut context)
-        } else {
-            args.get(2).unwrap().eval(&mut context)
-        }
-    } else { panic!() }
+fn if_special(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    match eval_arg(context, args, 0)? {
+        Type::Bool(true) => eval_arg(context, args, 1),
+        Type::Bool(false) if args.len() < 3 => Ok(Type::Nil),
+        Type::Bool(false) => eval_arg(context, args, 2),
+        other => Err(EvalError::runtime(format!("Bool expected -> {}", other)))
+    }
 }
 
 /**
 (> 10 20)
 -> false
 */
-fn gt(mut context: &mut Context, args:& [Type]) -> Type {
-    let left = args.get(0).unwrap().eval(&mut context);
-    let right = args.get(1).unwrap().eval(&mut context);
-
-    if let (Type::Number(l), Type::Number(r)) = (left, right) {
-        Type::Bool(l > r)
-    } else {
-        panic!()
-    }
+fn gt(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let left = number(arg(args, 0)?)?;
+    let right = number(arg(args, 1)?)?;
+    Ok(Type::Bool(left > right))
 }
 
-fn eq(mut context: &mut Context, args:& [Type]) -> Type {
-    let left = args.get(0).unwrap().eval(&mut context);
-    let right = args.get(1).unwrap().eval(&mut context);
+fn eq(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let left = arg(args, 0)?;
+    let right = arg(args, 1)?;
 
-    Type::Bool(left.eq(&right))
+    Ok(Type::Bool(left.eq(right)))
 }
 
-fn and(mut context: &mut Context, args:& [Type]) -> Type {
-    let left = args.get(0).unwrap().eval(&mut context);
-    let right = args.get(1).unwrap().eval(&mut context);
+fn and(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let left = arg(args, 0)?;
+    let right = arg(args, 1)?;
     match (left, right) {
-        (Type::Bool(true), Type::Bool(true)) => Type::Bool(true),
-        (_,_) => Type::Bool(false)
+        (Type::Bool(true), Type::Bool(true)) => Ok(Type::Bool(true)),
+        (_,_) => Ok(Type::Bool(false))
     }
 }
 
-fn or(mut context: &mut Context, args:& [Type]) -> Type {
+fn or(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
     // no short circuit - i'm too lazy
-    let left = args.get(0).unwrap().eval(&mut context);
-    let right = args.get(1).unwrap().eval(&mut context);
+    let left = arg(args, 0)?;
+    let right = arg(args, 1)?;
     match (left, right) {
-        (Type::Bool(left), Type::Bool(right)) => Type::Bool(left || right),
-        (_,_) => Type::Bool(false)
+        (Type::Bool(left), Type::Bool(right)) => Ok(Type::Bool(*left || *right)),
+        (_,_) => Ok(Type::Bool(false))
+    }
+}
+
+fn not(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    match arg(args, 0)? {
+        Type::Bool(arg) => Ok(Type::Bool(!arg)),
+        other => Err(EvalError::runtime(format!("Bool expected -> {}", other)))
+    }
+}
+
+/**
+(throw (ex-info "Not found" 42))
+*/
+fn throw(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    Err(EvalError::thrown(arg(args, 0)?.clone()))
+}
+
+/**
+(ex-info "Not found" (list 1 2))
+-> (ex-info "Not found" (1 2) nil)
+*/
+fn ex_info(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let message = arg(args, 0)?.clone();
+    let data = args.get(1).cloned().unwrap_or(Type::Nil);
+    Ok(lisp::ex_info(message, data, Type::Nil))
+}
+
+// nil for anything that isn't an ex-info
+fn ex_info_part(args:& [Type], i: usize) -> Result<Type, EvalError> {
+    match arg(args, 0)? {
+        Type::List(elems) if lisp::is_ex_info(elems) => Ok(elems[i].clone()),
+        _ => Ok(Type::Nil)
+    }
+}
+
+fn ex_message(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    ex_info_part(args, 1)
+}
+
+fn ex_data(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    ex_info_part(args, 2)
+}
+
+/**
+(try (car 1) (catch e (ex-stack e)))
+-> ("car")
+*/
+fn ex_stack(_context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    ex_info_part(args, 3)
+}
+
+/**
+(try
+    (car 1)
+    (catch e (ex-message e))
+    (finally (prn "done")))
+Catches both values passed to `throw` and interpreter errors,
+`finally` forms run whether body succeeded or not.
+*/
+fn try_special(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let mut body = vec![];
+    let mut catch = None;
+    let mut finally = None;
+    for form in args {
+        match form {
+            Type::List(elems) if elems.first() == Some(&Type::Symbol(String::from("catch"))) => catch = Some(elems),
+            Type::List(elems) if elems.first() == Some(&Type::Symbol(String::from("finally"))) => finally = Some(elems),
+            _ => body.push(form.clone())
+        }
+    }
+    // a malformed catch is reported before anything runs
+    let catch = match catch {
+        Some(catch) => Some((symbol(arg(catch, 1)?)?.clone(), &catch[2..])),
+        None => None
+    };
+    let mut result = eval_body(context, &body);
+    if let (Err(error), Some((name, handler))) = (&result, catch) {
+        // the error is bound only while the handler runs, definitions made by it are kept
+        let previous = context.insert(name.clone(), error.to_value());
+        result = eval_body(context, handler);
+        match previous {
+            Some(value) => context.insert(name, value),
+            None => context.remove(&name)
+        };
+    }
+    if let Some(finally) = finally {
+        eval_body(context, &finally[1..])?;
+    }
+    result
+}
+
+fn catch_outside_try(_context: &mut Context, _args:& [Type]) -> Result<Type, EvalError> {
+    Err(EvalError::runtime("catch and finally can only be used inside try"))
+}
+
+// state of `load` and `require`, the leading space keeps it out of reach of symbols like namespace state
+pub const FILE: &str = " file";
+const LOADED: &str = " loaded-libs";
+const LOADING: &str = " loading-libs";
+
+// `FILE` holds the file being loaded, relative paths in `load` and `require` start from its directory
+fn current_dir(context: &Context) -> PathBuf {
+    match context.get(FILE) {
+        Some(Type::Str(file)) => Path::new(file).parent().map(Path::to_path_buf).unwrap_or_default(),
+        _ => PathBuf::new()
+    }
+}
+
+/// Evaluates a file into `context` with `FILE` bound to its path while it runs,
+/// the namespace current before the file is restored after it
+pub fn load_file(context: &mut Context, path: &Path) -> Result<Type, EvalError> {
+    load_file_in_ns(context, path).0
+}
+
+// also returns the namespace the file ended in
+fn load_file_in_ns(context: &mut Context, path: &Path) -> (Result<Type, EvalError>, String) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return (Err(EvalError::runtime(format!("Cannot read {} -> {}", path.display(), e))), namespace::current(context))
+    };
+    let name = path.to_string_lossy().to_string();
+    let previous = context.insert(String::from(FILE), Type::Str(name.clone()));
+    let ns = namespace::current(context);
+    let result = lisp::eval_source(&source, &name, context);
+    let file_ns = namespace::current(context);
+    namespace::switch(context, &ns);
+    match previous {
+        Some(file) => context.insert(String::from(FILE), file),
+        None => context.remove(FILE)
+    };
+    (result, file_ns)
+}
+
+/**
+(load "lib/utils.lisp")
+Path is relative to the file calling `load`
+*/
+fn load(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let path = match arg(args, 0)? {
+        Type::Str(path) => path,
+        other => return Err(EvalError::runtime(format!("String expected -> {}", other)))
+    };
+    let path = current_dir(context).join(path);
+    load_file(context, &path)
+}
+
+// directory of the requiring file, then `LESP_PATH` entries
+fn find_library(context: &Context, name: &str) -> Result<PathBuf, EvalError> {
+    let file = if name.ends_with(".lisp") {
+        String::from(name)
+    } else {
+        format!("{}.lisp", name)
+    };
+    let mut dirs = vec![current_dir(context)];
+    if let Some(paths) = env::var_os("LESP_PATH") {
+        dirs.extend(env::split_paths(&paths));
     }
+    dirs.iter()
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+        .ok_or_else(|| EvalError::runtime(format!("Library not found -> {}", name)))
 }
 
-fn not(mut context: &mut Context, args:& [Type]) -> Type {
-    let arg = args.get(0).unwrap().eval(&mut context);
-    match arg {
-        Type::Bool(arg) => Type::Bool(!arg),
-        _ => panic!()
+fn libraries(context: &Context, key: &str) -> Vec<Type> {
+    match context.get(key) {
+        Some(Type::List(libraries)) => libraries.clone(),
+        _ => vec![]
+    }
+}
+
+enum Refer {
+    Nothing,
+    All,
+    Names(Vec<String>)
+}
+
+// `:as alias` and `:refer (names...)` or `:refer :all`, options aren't evaluated
+fn require_options(args: &[Type]) -> Result<(Option<String>, Refer), EvalError> {
+    let mut alias = None;
+    let mut refer = Refer::Nothing;
+    for pair in args.chunks(2) {
+        let value = lisp::strip_locations(arg(pair, 1)?);
+        match symbol(&pair[0])?.as_str() {
+            ":as" => alias = Some(symbol(&value)?.clone()),
+            ":refer" => refer = match value {
+                Type::Symbol(all) if all == ":all" => Refer::All,
+                Type::List(names) => Refer::Names(names.iter().map(|name| symbol(name).cloned()).collect::<Result<_, _>>()?),
+                other => return Err(EvalError::runtime(format!("List of names expected -> {}", other)))
+            },
+            other => return Err(EvalError::runtime(format!("Unknown require option -> {}", other)))
+        }
+    }
+    Ok((alias, refer))
+}
+
+/**
+(require 'utils)
+(require 'utils :as u :refer (helper))
+Loads `utils.lisp` unless it was already required, circular requires are errors.
+`:as` and `:refer` need the library to start a namespace with `ns`
+*/
+fn require(context: &mut Context, args:& [Type]) -> Result<Type, EvalError> {
+    let name = match eval_arg(context, args, 0)? {
+        Type::Symbol(name) | Type::Str(name) => name,
+        other => return Err(EvalError::runtime(format!("Library name expected -> {}", other)))
+    };
+    let (alias, refer) = require_options(&args[1..])?;
+    let path = find_library(context, &name)?;
+    let key = Type::Str(fs::canonicalize(&path).unwrap_or_else(|_| path.clone()).to_string_lossy().to_string());
+    // loaded libraries are kept as (path namespace)
+    let loaded = libraries(context, LOADED).into_iter().find_map(|lib| match lib {
+        Type::List(entry) => match entry.as_slice() {
+            [path, ns] if *path == key => Some(ns.clone()),
+            _ => None
+        },
+        _ => None
+    });
+    let ns = match loaded {
+        Some(ns) => ns,
+        None => {
+            let loading = libraries(context, LOADING);
+            if loading.contains(&key) {
+                let chain: Vec<String> = loading.iter().chain(Some(&key)).map(|lib| format!("{:?}", lib)).collect();
+                return Err(EvalError::runtime(format!("Circular require -> {}", chain.join(" -> "))));
+            }
+            let mut now_loading = loading.clone();
+            now_loading.push(key.clone());
+            context.insert(String::from(LOADING), Type::List(now_loading));
+            let (result, file_ns) = load_file_in_ns(context, &path);
+            context.insert(String::from(LOADING), Type::List(loading));
+            result?;
+            let ns = if file_ns == namespace::current(context) {
+                Type::Nil
+            } else {
+                Type::Symbol(file_ns)
+            };
+            let mut loaded = libraries(context, LOADED);
+            loaded.push(Type::List(vec![key, ns.clone()]));
+            context.insert(String::from(LOADED), Type::List(loaded));
+            ns
+        }
+    };
+    if let (None, Refer::Nothing) = (&alias, &refer) {
+        return Ok(ns);
+    }
+    let ns = match ns {
+        Type::Symbol(ns) => ns,
+        _ => return Err(EvalError::runtime(format!("Library has no namespace -> {}", name)))
+    };
+    if let Some(alias) = alias {
+        namespace::add_alias(context, &alias, &ns);
     }
+    let names = match refer {
+        Refer::Nothing => vec![],
+        Refer::All => namespace::exports(context, &ns),
+        Refer::Names(names) => names
+    };
+    for name in names {
+        let qualified = format!("{}/{}", ns, name);
+        namespace::check_access(context, &qualified)?;
+        let value = context.get(&qualified).cloned()
+            .ok_or_else(|| EvalError::runtime(format!("Symbol not found -> {:?}", qualified)))?;
+        // referred names aren't exported again
+        namespace::define(context, &name, value, true);
+    }
+    Ok(Type::Symbol(ns))
+}
+
+fn add_to_context(name: &str, context: &mut Context, kind: fn(Rc<Native>) -> FunctionType,
+                  value: fn(&mut Context, &[Type]) -> Result<Type, EvalError>, arity: Arity, doc: &str) {
+    let native = Native { name: String::from(name), arity, doc: String::from(doc), f: Rc::new(value) };
+    context.insert(String::from(name), Type::Function(kind(Rc::new(native))));
 }
 
-fn add_to_context(name: &str, context: &mut Context, value: NativeFunction) {
-    let name = String::from(name);
-    context.insert(name.clone(), Type::Function(FunctionType::Native(name, value)));
+/// Natives together with the standard library from `res/init.lisp`
+pub fn init_context_with_prelude() -> Context {
+    let mut context = init_context();
+    let prelude = String::from_utf8_lossy(include_bytes!("../res/init.lisp"));
+    lisp::eval_source(&prelude, "init.lisp", &mut context).expect("Cannot evaluate init.lisp");
+    namespace::hide_private(&mut context, namespace::USER);
+    context
 }
 
 pub fn init_context() -> Context {
     macro_rules! add {
-        ( $( $n:expr , $f:expr  ),* ) => {{
-            let mut context: HashMap<String, Type> = HashMap::new();
+        ( $context:ident, $kind:path; $( $n:expr , $f:expr , $arity:expr , $doc:expr ),* ) => {{
             $(
-                add_to_context($n, &mut context, $f);
+                add_to_context($n, &mut $context, $kind, $f, $arity, $doc);
             )*
-            context
         }};
     }
-
-    add!["def", def_special,
-         "let", let_special,
-         "fn", fn_special,
-         "if", if_special,
-         "macro", macro_scpecial,
-         "*", mult,
-         "+", add,
-         "-", sub,
-         ">", gt,
-         "quote", quote,
-         "list", list,
-         "car", car,
-         "cdr", cdr,
-         "push", push,
-         "dbg", dbg,
-         "prn", prn,
-         "is-list", is_list,
-         "cons", cons,
-         "apply", apply,
-         "eq", eq,
-         "and", and,
-         "or", or,
-         "not", not]
-}
\ No newline at end of file
+    let exactly = Arity::exactly;
+    let at_least = Arity::at_least;
+    let between = Arity::between;
+    let mut context: Context = HashMap::new();
+
+    // special forms get their arguments as they are written
+    add!(context, FunctionType::Special;
+         "def", def_special, exactly(2), "Binds a name in the current namespace",
+         "def-", def_private, exactly(2), "Binds a name other namespaces can't refer to",
+         "ns", ns_special, exactly(1), "Starts a namespace, following definitions go to it",
+         "let", let_special, at_least(1), "Evaluates the body with local bindings",
+         "fn", fn_special, at_least(2), "Creates a named function",
+         "if", if_special, between(2, 3), "Evaluates the second form when the first is true, the third or nil otherwise",
+         "macro", macro_scpecial, at_least(2), "Creates a macro",
+         "hmacro", hmacro_special, at_least(2), "Creates a macro whose template bindings can't capture user symbols",
+         "try", try_special, at_least(0), "Evaluates the body, catch handles errors and finally always runs",
+         "catch", catch_outside_try, at_least(0), "Handles errors of the enclosing try",
+         "finally", catch_outside_try, at_least(0), "Runs after the body of the enclosing try",
+         "require", require, at_least(1), "Loads a library once, with :as alias and :refer names",
+         "quote", quote, exactly(1), "Returns the form unevaluated",
+         "quasiquote", quasiquote, exactly(1), "Template where unquote and unquote-splicing are evaluated",
+         "unquote", unquote, at_least(0), "Evaluated part of a quasiquote template",
+         "unquote-splicing", unquote, at_least(0), "Evaluated list spliced into a quasiquote template",
+         "dbg", dbg, exactly(1), "Prints a form with its value and returns the value");
+    // functions get evaluated arguments, so they can be passed to apply, map and reduce
+    add!(context, FunctionType::Native;
+         "in-ns", in_ns, exactly(1), "Switches to a namespace given as a symbol",
+         "gensym", gensym_native, between(0, 1), "Unique symbol, optionally starting with a prefix",
+         "macroexpand-1", macroexpand_1_native, exactly(1), "Expands the macro call once",
+         "macroexpand", macroexpand_native, exactly(1), "Expands the macro call until it isn't one",
+         "macroexpand-all", macroexpand_all_native, exactly(1), "Expands all macro calls in a form",
+         "throw", throw, exactly(1), "Raises a value as an error",
+         "ex-info", ex_info, between(1, 2), "Error value with a message and optional data",
+         "ex-message", ex_message, exactly(1), "Message of an ex-info",
+         "ex-data", ex_data, exactly(1), "Data of an ex-info",
+         "ex-stack", ex_stack, exactly(1), "Functions an ex-info was raised through",
+         "load", load, exactly(1), "Evaluates a file relative to the current one",
+         "*", mult, at_least(0), "Product of numbers",
+         "+", add, at_least(0), "Sum of numbers",
+         "-", sub, at_least(1), "Subtracts the rest of the numbers from the first",
+         ">", gt, exactly(2), "True when the first number is greater",
+         "list", list, at_least(0), "List of the arguments",
+         "car", car, between(0, 1), "First element of a list, nil when it's empty",
+         "cdr", cdr, between(0, 1), "List without its first element",
+         "get", get, exactly(2), "Element at an index, nil when it's out of range",
+         "push", push, exactly(2), "Appends a value to the end of a list",
+         "prn", prn, exactly(1), "Prints a value and returns it",
+         "pprint", pprint, exactly(1), "Prints a value laid out within 80 columns and returns it",
+         "pr-str", pr_str, at_least(0), "Values printed the way they are written, separated by spaces",
+         "read-string", read_string, exactly(1), "Reads the first form of a string",
+         "is-list", is_list, exactly(1), "True for lists",
+         "nil?", is_nil, exactly(1), "True for nil",
+         "cons", cons, exactly(2), "Prepends a value to a list",
+         "apply", apply, exactly(2), "Calls a function with the elements of a list as arguments",
+         "eq", eq, exactly(2), "True when the values are equal",
+         "and", and, exactly(2), "True when both values are true",
+         "or", or, exactly(2), "True when either value is true",
+         "not", not, exactly(1), "Negates a bool");
+    context
+}
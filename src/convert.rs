@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+use crate::interpreter::{Error, Value};
+
+/// Rust value that can be passed to lesp
+pub trait IntoLisp {
+    fn into_lisp(self) -> Value;
+}
+
+/// Rust value that can be taken from lesp, fails with `Number expected -> "a"` style errors
+pub trait FromLisp: Sized {
+    fn from_lisp(value: Value) -> Result<Self, Error>;
+}
+
+fn expected<T>(what: &str, value: &Value) -> Result<T, Error> {
+    Err(Error::new(format!("{} expected -> {}", what, value)))
+}
+
+impl IntoLisp for Value {
+    fn into_lisp(self) -> Value {
+        self
+    }
+}
+
+impl FromLisp for Value {
+    fn from_lisp(value: Value) -> Result<Value, Error> {
+        Ok(value)
+    }
+}
+
+impl IntoLisp for () {
+    fn into_lisp(self) -> Value {
+        Value::Nil
+    }
+}
+
+// integers that always fit in a lesp number
+macro_rules! into_number {
+    ( $( $t:ty ),* ) => {$(
+        impl IntoLisp for $t {
+            fn into_lisp(self) -> Value {
+                Value::Number(i64::from(self))
+            }
+        }
+    )*};
+}
+
+into_number!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! from_number {
+    ( $( $t:ty ),* ) => {$(
+        impl FromLisp for $t {
+            fn from_lisp(value: Value) -> Result<$t, Error> {
+                match value {
+                    Value::Number(n) => <$t>::try_from(n).map_err(|_| Error::new(format!("Number out of range -> {}", n))),
+                    other => expected("Number", &other)
+                }
+            }
+        }
+    )*};
+}
+
+from_number!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoLisp for bool {
+    fn into_lisp(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromLisp for bool {
+    fn from_lisp(value: Value) -> Result<bool, Error> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            other => expected("Bool", &other)
+        }
+    }
+}
+
+impl IntoLisp for String {
+    fn into_lisp(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl IntoLisp for &str {
+    fn into_lisp(self) -> Value {
+        Value::Str(String::from(self))
+    }
+}
+
+impl FromLisp for String {
+    fn from_lisp(value: Value) -> Result<String, Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            other => expected("String", &other)
+        }
+    }
+}
+
+impl<T: IntoLisp> IntoLisp for Vec<T> {
+    fn into_lisp(self) -> Value {
+        Value::List(self.into_iter().map(IntoLisp::into_lisp).collect())
+    }
+}
+
+/// nil converts to an empty `Vec`
+impl<T: FromLisp> FromLisp for Vec<T> {
+    fn from_lisp(value: Value) -> Result<Vec<T>, Error> {
+        match value {
+            Value::List(elems) => elems.into_iter().map(T::from_lisp).collect(),
+            Value::Nil => Ok(vec![]),
+            other => expected("List", &other)
+        }
+    }
+}
+
+/// `None` is nil
+impl<T: IntoLisp> IntoLisp for Option<T> {
+    fn into_lisp(self) -> Value {
+        self.map_or(Value::Nil, IntoLisp::into_lisp)
+    }
+}
+
+impl<T: FromLisp> FromLisp for Option<T> {
+    fn from_lisp(value: Value) -> Result<Option<T>, Error> {
+        match value {
+            Value::Nil => Ok(None),
+            other => T::from_lisp(other).map(Some)
+        }
+    }
+}
+
+/// List of `(key value)` pairs in no particular order
+impl<K: IntoLisp, V: IntoLisp> IntoLisp for HashMap<K, V> {
+    fn into_lisp(self) -> Value {
+        Value::List(self.into_iter().map(|pair| pair.into_lisp()).collect())
+    }
+}
+
+impl<K: FromLisp + Eq + Hash, V: FromLisp> FromLisp for HashMap<K, V> {
+    fn from_lisp(value: Value) -> Result<HashMap<K, V>, Error> {
+        Vec::<(K, V)>::from_lisp(value).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+// tuples are lists of exactly their length
+macro_rules! tuple {
+    ( $n:expr ; $( $t:ident ),* ) => {
+        impl<$( $t: IntoLisp ),*> IntoLisp for ($( $t, )*) {
+            #[allow(non_snake_case)]
+            fn into_lisp(self) -> Value {
+                let ($( $t, )*) = self;
+                Value::List(vec![$( $t.into_lisp() ),*])
+            }
+        }
+
+        impl<$( $t: FromLisp ),*> FromLisp for ($( $t, )*) {
+            fn from_lisp(value: Value) -> Result<Self, Error> {
+                match value {
+                    Value::List(elems) if elems.len() == $n => {
+                        let mut elems = elems.into_iter();
+                        Ok(($( $t::from_lisp(elems.next().unwrap_or(Value::Nil))?, )*))
+                    },
+                    other => expected(concat!("List of ", $n, " elements"), &other)
+                }
+            }
+        }
+    };
+}
+
+tuple!(1; A);
+tuple!(2; A, B);
+tuple!(3; A, B, C);
+tuple!(4; A, B, C, D);
+
+/// What functions wrapped by `Interpreter::register_fn` return, a value or a `Result` of one
+pub trait IntoLispResult {
+    fn into_lisp_result(self) -> Result<Value, Error>;
+}
+
+impl<T: IntoLisp> IntoLispResult for T {
+    fn into_lisp_result(self) -> Result<Value, Error> {
+        Ok(self.into_lisp())
+    }
+}
+
+impl<T: IntoLisp> IntoLispResult for Result<T, Error> {
+    fn into_lisp_result(self) -> Result<Value, Error> {
+        self.map(IntoLisp::into_lisp)
+    }
+}
+
+// integers that may not fit in a lesp number, returning one that doesn't is an error
+macro_rules! into_number_checked {
+    ( $( $t:ty ),* ) => {$(
+        impl IntoLispResult for $t {
+            fn into_lisp_result(self) -> Result<Value, Error> {
+                i64::try_from(self).map(Value::Number).map_err(|_| Error::new(format!("Number out of range -> {}", self)))
+            }
+        }
+
+        impl IntoLispResult for Result<$t, Error> {
+            fn into_lisp_result(self) -> Result<Value, Error> {
+                self.and_then(IntoLispResult::into_lisp_result)
+            }
+        }
+    )*};
+}
+
+into_number_checked!(isize, u64, usize);
+
+/**
+Rust function that can be called from lesp, implemented for functions of up to
+five arguments taking `FromLisp` types. `Args` is the tuple of argument types
+*/
+pub trait NativeFn<Args> {
+    const ARITY: usize;
+
+    /// Gets exactly `ARITY` arguments
+    fn call(&self, args: Vec<Value>) -> Result<Value, Error>;
+}
+
+macro_rules! native_fn {
+    ( $n:expr ; $( $t:ident ),* ) => {
+        impl<F, R, $( $t ),*> NativeFn<($( $t, )*)> for F
+        where F: Fn($( $t ),*) -> R, R: IntoLispResult, $( $t: FromLisp ),* {
+            const ARITY: usize = $n;
+
+            #[allow(unused_mut, unused_variables)]
+            fn call(&self, args: Vec<Value>) -> Result<Value, Error> {
+                let mut args = args.into_iter();
+                self($( $t::from_lisp(args.next().unwrap_or(Value::Nil))? ),*).into_lisp_result()
+            }
+        }
+    };
+}
+
+native_fn!(0;);
+native_fn!(1; A);
+native_fn!(2; A, B);
+native_fn!(3; A, B, C);
+native_fn!(4; A, B, C, D);
+native_fn!(5; A, B, C, D, E);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(i32::from_lisp(Value::Number(-5)), Ok(-5));
+        assert_eq!(u8::from_lisp(Value::Number(300)), Err(Error::new("Number out of range -> 300")));
+        assert_eq!(i64::from_lisp(Value::Str(String::from("a"))), Err(Error::new("Number expected -> \"a\"")));
+        assert_eq!(Vec::<bool>::from_lisp(Value::Nil), Ok(vec![]));
+        assert_eq!(Option::<String>::from_lisp(Value::Nil), Ok(None));
+        assert_eq!(<(String, i64)>::from_lisp(vec![Value::Str(String::from("a")), Value::Number(1)].into_lisp()),
+                   Ok((String::from("a"), 1)));
+        assert_eq!(<(i64, i64)>::from_lisp(vec![1].into_lisp()), Err(Error::new("List of 2 elements expected -> (1)")));
+        let mut map = HashMap::new();
+        map.insert(String::from("a"), vec![Some(1), None]);
+        assert_eq!(map.clone().into_lisp().to_string(), "((\"a\" (1 nil)))");
+        assert_eq!(HashMap::from_lisp(map.clone().into_lisp()), Ok(map));
+        assert_eq!(("x", true, ()).into_lisp().to_string(), "(\"x\" true nil)");
+        assert_eq!(5usize.into_lisp_result(), Ok(Value::Number(5)));
+        assert_eq!(u64::MAX.into_lisp_result(), Err(Error::new("Number out of range -> 18446744073709551615")));
+        assert_eq!(Ok::<_, Error>(-3isize).into_lisp_result(), Ok(Value::Number(-3)));
+    }
+}
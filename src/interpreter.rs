@@ -0,0 +1,378 @@
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::built_in;
+use crate::convert::NativeFn;
+use crate::lisp::{self, Arity, Context, ErrorKind, EvalError, FunctionType, Native, Type};
+use crate::namespace;
+use crate::pretty;
+
+/**
+Value passed between Rust and lesp. It is kept apart from the interpreter's own
+representation, so changes to the interpreter don't break embedders.
+*/
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(i64),
+    Str(String),
+    Symbol(String),
+    List(Vec<Value>),
+    Function(Function)
+}
+
+impl Value {
+    pub(crate) fn from_type(value: &Type) -> Value {
+        match value {
+            Type::Nil => Value::Nil,
+            Type::Bool(b) => Value::Bool(*b),
+            Type::Number(n) => Value::Number(*n),
+            Type::Str(s) => Value::Str(s.clone()),
+            Type::Symbol(s) => Value::Symbol(s.clone()),
+            Type::List(elems) => Value::List(elems.iter().map(Value::from_type).collect()),
+            Type::Function(f) => Value::Function(Function(f.clone())),
+            Type::Located(form, _) => Value::from_type(form)
+        }
+    }
+
+    pub(crate) fn to_type(&self) -> Type {
+        match self {
+            Value::Nil => Type::Nil,
+            Value::Bool(b) => Type::Bool(*b),
+            Value::Number(n) => Type::Number(*n),
+            Value::Str(s) => Type::Str(s.clone()),
+            Value::Symbol(s) => Type::Symbol(s.clone()),
+            Value::List(elems) => Type::List(elems.iter().map(Value::to_type).collect()),
+            Value::Function(f) => Type::Function(f.0.clone())
+        }
+    }
+
+    /// Lays the value out within `width` columns
+    pub fn pretty(&self, width: usize) -> String {
+        pretty::pretty(&self.to_type(), width)
+    }
+}
+
+/// Same syntax the reader accepts, functions print as `#<fn name>`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_type().fmt(f)
+    }
+}
+
+/// Function defined in lesp or built into the interpreter
+#[derive(Clone, PartialEq)]
+pub struct Function(FunctionType);
+
+impl Function {
+    pub fn name(&self) -> &str {
+        match &self.0 {
+            FunctionType::Native(native) | FunctionType::Special(native) => &native.name,
+            FunctionType::UserDefined(f) => &f.name
+        }
+    }
+
+    /// Docstring of a native, empty for functions defined in lesp
+    pub fn doc(&self) -> &str {
+        match &self.0 {
+            FunctionType::Native(native) | FunctionType::Special(native) => &native.doc,
+            FunctionType::UserDefined(_) => ""
+        }
+    }
+
+    pub fn is_macro(&self) -> bool {
+        self.0.is_macro()
+    }
+
+    /**
+    native, 0 to 1 arguments
+    special form, 2 to 3 arguments
+    fn (a b rest...)
+    macro (body...)
+    */
+    pub fn signature(&self) -> String {
+        let f = match &self.0 {
+            FunctionType::Native(native) => return format!("native, {} arguments", native.arity),
+            FunctionType::Special(native) => return format!("special form, {} arguments", native.arity),
+            FunctionType::UserDefined(f) => f
+        };
+        let mut params: Vec<String> = f.args.iter().map(Type::to_string).collect();
+        if let Some(vararg) = &f.vararg {
+            params.push(format!("{}...", vararg));
+        }
+        format!("{} ({})", if f.is_macro { "macro" } else { "fn" }, params.join(" "))
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Error raised while reading or evaluating, displayed with its location and stack trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(EvalError);
+
+impl Error {
+    /// Error with `message`, for natives registered by the host
+    pub fn new<S: Into<String>>(message: S) -> Error {
+        Error(EvalError::runtime(message))
+    }
+
+    /// Message without location and stack, `Uncaught value` for values passed to `throw`
+    pub fn message(&self) -> String {
+        match &self.0.kind {
+            ErrorKind::Runtime(message) => message.clone(),
+            ErrorKind::Thrown(value) => format!("Uncaught {}", value)
+        }
+    }
+
+    /// Value passed to `throw`, `None` for errors raised by the interpreter
+    pub fn thrown(&self) -> Option<Value> {
+        match &self.0.kind {
+            ErrorKind::Thrown(value) => Some(Value::from_type(value)),
+            ErrorKind::Runtime(_) => None
+        }
+    }
+
+    /// `source:line:col` where the error happened
+    pub fn location(&self) -> Option<String> {
+        self.0.location.as_ref().map(ToString::to_string)
+    }
+
+    /// Functions the error unwound through, innermost first
+    pub fn stack(&self) -> Vec<String> {
+        self.0.stack.iter().map(ToString::to_string).collect()
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(error: EvalError) -> Error {
+        Error(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/**
+Interpreter with its own global definitions.
+
+```
+use lesp::{Interpreter, Value};
+
+let mut lisp = Interpreter::with_prelude();
+lisp.set("limit", Value::Number(3));
+lisp.eval_str("(defn below-limit (xs) (map xs (fn _ (x) (> limit x))))").unwrap();
+let result = lisp.call("below-limit", &[Value::List(vec![Value::Number(1), Value::Number(5)])]);
+assert_eq!(result, Ok(Value::List(vec![Value::Bool(true), Value::Bool(false)])));
+```
+*/
+pub struct Interpreter {
+    context: Context
+}
+
+impl Interpreter {
+    /// Builtins only, without the standard library
+    pub fn new() -> Interpreter {
+        Interpreter { context: built_in::init_context() }
+    }
+
+    /// Builtins and the standard library from `res/init.lisp`
+    pub fn with_prelude() -> Interpreter {
+        Interpreter { context: built_in::init_context_with_prelude() }
+    }
+
+    /// Evaluates all forms in `input` and returns the value of the last one
+    pub fn eval_str(&mut self, input: &str) -> Result<Value, Error> {
+        self.eval_source(input, "<eval>")
+    }
+
+    /// Same as `eval_str`, errors point to `source:line:col`
+    pub fn eval_source(&mut self, input: &str, source: &str) -> Result<Value, Error> {
+        Ok(Value::from_type(&lisp::eval_source(input, source, &mut self.context)?))
+    }
+
+    /// Evaluates a file, `load` and `require` in it are relative to its directory
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Value, Error> {
+        Ok(Value::from_type(&built_in::load_file(&mut self.context, path.as_ref())?))
+    }
+
+    /// Value bound to `name`, qualified names like `ns/name` included
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.context.get(name).cloned()
+            .or_else(|| namespace::resolve(&self.context, name).ok())
+            .map(|value| Value::from_type(&value))
+    }
+
+    /// Binds `name` in the current namespace, same as `def`
+    pub fn set(&mut self, name: &str, value: Value) {
+        namespace::define(&mut self.context, name, value.to_type(), false);
+    }
+
+    /**
+    Binds `name` to a native implemented by `f`, which gets evaluated arguments like any function.
+    The number of arguments is checked against `arity` before `f` is called
+    */
+    pub fn register<F>(&mut self, name: &str, arity: Arity, doc: &str, f: F)
+    where F: Fn(&[Value]) -> Result<Value, Error> + 'static {
+        self.register_native(name, arity, doc, move |args| f(&args));
+    }
+
+    /**
+    Binds `name` to an ordinary Rust function, arguments are converted with `FromLisp`
+    and the result with `IntoLisp`. Arity comes from the function's signature
+
+    ```
+    let mut lisp = lesp::Interpreter::new();
+    lisp.register_fn("repeat", "Repeats a string", |s: String, n: usize| s.repeat(n));
+    assert_eq!(lisp.eval_str("(repeat \"ab\" 2)"), Ok(lesp::Value::Str(String::from("abab"))));
+    assert_eq!(lisp.eval_str("(repeat 2 \"ab\")").unwrap_err().message(), "String expected -> 2");
+    ```
+    */
+    pub fn register_fn<Args, F>(&mut self, name: &str, doc: &str, f: F)
+    where F: NativeFn<Args> + 'static {
+        self.register_native(name, Arity::exactly(F::ARITY), doc, move |args| f.call(args));
+    }
+
+    fn register_native<F>(&mut self, name: &str, arity: Arity, doc: &str, f: F)
+    where F: Fn(Vec<Value>) -> Result<Value, Error> + 'static {
+        let native = Native {
+            name: String::from(name),
+            arity,
+            doc: String::from(doc),
+            f: Rc::new(move |_: &mut Context, args: &[Type]| {
+                f(args.iter().map(Value::from_type).collect()).map(|value| value.to_type()).map_err(|e| e.0)
+            })
+        };
+        self.set(name, Value::Function(Function(FunctionType::Native(Rc::new(native)))));
+    }
+
+    /// Calls the function bound to `name` with `args`
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, Error> {
+        let f = match Type::Symbol(String::from(name)).eval(&mut self.context)? {
+            Type::Function(f) if !f.is_macro() => f,
+            other => return Err(Error(EvalError::runtime(format!("Function expected -> {}", other))))
+        };
+        Ok(Value::from_type(&f.call(&mut self.context, args.iter().map(Value::to_type).collect())?))
+    }
+
+    /// Names of all bindings, sorted
+    pub fn names(&self) -> Vec<String> {
+        // names with a space are kept by namespaces and libraries for themselves
+        let mut names: Vec<String> = self.context.keys().filter(|name| !name.contains(' ')).cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpreter() {
+        let mut lisp = Interpreter::with_prelude();
+        assert_eq!(lisp.eval_str("(+ 1 2)"), Ok(Value::Number(3)));
+        lisp.set("items", Value::List(vec![Value::Str(String::from("a")), Value::Symbol(String::from("b"))]));
+        assert_eq!(lisp.eval_str("(len items)"), Ok(Value::Number(2)));
+        assert_eq!(lisp.call("cons", &[Value::Nil, Value::List(vec![Value::Symbol(String::from("x"))])]),
+                   Ok(Value::List(vec![Value::Nil, Value::Symbol(String::from("x"))])));
+        assert_eq!(lisp.call("square", &[Value::Number(7)]), Ok(Value::Number(49)));
+        assert_eq!(lisp.get("missing"), None);
+        assert_eq!(lisp.get("items").map(|items| items.to_string()), Some(String::from("(\"a\" b)")));
+        match lisp.get("reduce") {
+            Some(Value::Function(f)) => assert_eq!((f.name(), f.signature().as_str()), ("reduce", "fn (elems arg...)")),
+            other => panic!("{:?}", other)
+        }
+        assert!(lisp.names().contains(&String::from("map")));
+        assert!(Interpreter::new().get("map").is_none());
+    }
+
+    #[test]
+    fn test_register() {
+        use std::cell::Cell;
+
+        let mut lisp = Interpreter::with_prelude();
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        lisp.register("count-call", Arity::between(0, 1), "Counts its calls", move |args| {
+            counter.set(counter.get() + 1);
+            match args {
+                [Value::Number(n)] => Ok(Value::Number(n * counter.get())),
+                [] => Ok(Value::Number(counter.get())),
+                _ => Err(Error::new("Number expected"))
+            }
+        });
+        assert_eq!(lisp.eval_str("(count-call (+ 1 2))"), Ok(Value::Number(3)));
+        assert_eq!(lisp.eval_str("(map '(1 2) (fn _ (a) (count-call)))"),
+                   Ok(Value::List(vec![Value::Number(2), Value::Number(3)])));
+        assert_eq!(calls.get(), 3);
+        let error = lisp.eval_str("(count-call 1 2)").unwrap_err();
+        assert_eq!(error.message(), "Wrong number of arguments to count-call -> expected 0 to 1, got 2");
+        assert_eq!(calls.get(), 3);
+        assert_eq!(lisp.eval_str("(try (count-call \"a\") (catch e (ex-message e)))"), Ok(Value::Str(String::from("Number expected"))));
+        match lisp.get("count-call") {
+            Some(Value::Function(f)) => assert_eq!((f.doc(), f.signature().as_str()), ("Counts its calls", "native, 0 to 1 arguments")),
+            other => panic!("{:?}", other)
+        }
+        match lisp.get("car") {
+            Some(Value::Function(f)) => assert_eq!(f.doc(), "First element of a list, nil when it's empty"),
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_register_fn() {
+        use std::collections::HashMap;
+
+        let mut lisp = Interpreter::with_prelude();
+        lisp.register_fn("add", "Adds two numbers", |a: i64, b: i64| a + b);
+        lisp.register_fn("checked-div", "Divides, errors on zero", |a: i64, b: i64| {
+            a.checked_div(b).ok_or_else(|| Error::new("Division by zero"))
+        });
+        lisp.register_fn("lookup", "Value of a key", |pairs: HashMap<String, i64>, key: String| pairs.get(&key).copied());
+        lisp.register_fn("answer", "", || 42u8);
+        lisp.register_fn("len", "", |s: String| s.len());
+        lisp.register_fn("huge", "", || u64::MAX);
+        assert_eq!(lisp.eval_str("(add 1 (answer))"), Ok(Value::Number(43)));
+        assert_eq!(lisp.call("add", &[Value::Number(1), Value::Number(2)]), Ok(Value::Number(3)));
+        assert_eq!(lisp.eval_str("(checked-div 7 2)"), Ok(Value::Number(3)));
+        assert_eq!(lisp.eval_str("(len \"abc\")"), Ok(Value::Number(3)));
+        assert_eq!(lisp.eval_str("(huge)").unwrap_err().message(), "Number out of range -> 18446744073709551615");
+        assert_eq!(lisp.eval_str("(checked-div 7 0)").unwrap_err().message(), "Division by zero");
+        assert_eq!(lisp.eval_str("(lookup '((\"a\" 1) (\"b\" 2)) \"b\")"), Ok(Value::Number(2)));
+        assert_eq!(lisp.eval_str("(lookup '((\"a\" 1)) \"c\")"), Ok(Value::Nil));
+        assert_eq!(lisp.eval_str("(add 1)").unwrap_err().message(), "Wrong number of arguments to add -> expected 2, got 1");
+        assert_eq!(lisp.eval_str("(add 1 'x)").unwrap_err().message(), "Number expected -> x");
+        assert_eq!(lisp.eval_str("(lookup '(1) \"a\")").unwrap_err().message(), "List of 2 elements expected -> 1");
+    }
+
+    #[test]
+    fn test_interpreter_errors() {
+        let mut lisp = Interpreter::new();
+        let error = lisp.eval_source("(car 1)", "script.lisp").unwrap_err();
+        assert_eq!(error.message(), "List expected -> 1");
+        assert_eq!(error.location(), Some(String::from("script.lisp:1:1")));
+        assert_eq!(error.stack(), vec![String::from("car (script.lisp:1:1)")]);
+        assert_eq!(lisp.eval_str("(throw '(1))").unwrap_err().thrown(), Some(Value::List(vec![Value::Number(1)])));
+        assert_eq!(lisp.call("nope", &[]).unwrap_err().message(), "Symbol not found -> \"nope\"");
+        assert_eq!(lisp.eval_str("(def x 1)").and_then(|_| lisp.call("x", &[])).unwrap_err().message(), "Function expected -> 1");
+        assert!(lisp.eval_file("no/such/file.lisp").unwrap_err().message().starts_with("Cannot read no/such/file.lisp"));
+    }
+}
@@ -1,79 +1,243 @@
-use regex::Regex;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::iter::Peekable;
+use std::str::CharIndices;
 
+use crate::parser::ParseError;
+
+/// Symbols and strings borrow from the input, strings are only copied when they contain escapes
 #[derive(Debug, Clone, PartialEq)]
-pub enum Tokens {
-    Symbol(String),
+pub enum Tokens<'a> {
+    Symbol(&'a str),
     OP,
     CP,
-    Number(u32)
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    /// `#_`, the next form is skipped by the parser
+    Discard,
+    Str(Cow<'a, str>),
+    Number(i64),
+    /// `; ...`, `#| ... |#` or a `#!` line, only kept by `parse_fsm_with_comments`
+    Comment(&'a str)
 }
 
-#[derive(Debug, Clone)]
-enum ParsingState {
-    Init, Number(u32), Symbol(String)
+/// 1-based line and column of a token in its source text, columns count characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize
 }
 
-pub fn parse_fsm(input: &String) -> Vec<Tokens> {
-    let mut current_state = ParsingState::Init;
-    let mut i = 0;
-    let mut tokens = vec![];
-    let numbers = Regex::new(r"[0-9]").unwrap();
-    let whitespace = Regex::new(r"[\s\n\r]").unwrap();
-    let letters_and_numbers = Regex::new(r"[-_a-zA-Z0-9.!>]").unwrap();
-    loop {
-        let current_char = if i >= input.len() {
-            None
+impl Default for Position {
+    fn default() -> Self {
+        Position { line: 1, col: 1 }
+    }
+}
+
+struct Cursor<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    position: Position
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, ch)| *ch)
+    }
+
+    // byte offset of the next char
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map(|(i, _)| *i).unwrap_or_else(|| self.input.len())
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let (_, ch) = self.chars.next()?;
+        if ch == '\n' {
+            self.position.line += 1;
+            self.position.col = 1;
         } else {
-            Some(&input[i..i+1])
-        };
-        match &current_state {
-            ParsingState::Init => {
-                match current_char {
-                    Some(ch) if whitespace.is_match(&ch) => (),
-                    Some("(") => {tokens.push(Tokens::OP);},
-                    Some(")") => {tokens.push(Tokens::CP);},
-                    Some(ch) if numbers.is_match(&ch) => {current_state = ParsingState::Number(ch.parse::<u32>().unwrap());},
-                    Some(ch) => {current_state = ParsingState::Symbol(String::from(ch));},
-                    None => return tokens
-                };
-                i+=1;
-            },
-            ParsingState::Number(num) => {
-                match current_char {
-                    Some(ch) if !numbers.is_match(&ch) => {
-                        tokens.push(Tokens::Number(*num));
-                        current_state = ParsingState::Init;
-                    },
-                    Some(ch) => {
-                        let current_number = ch.parse::<u32>().unwrap();
-                        current_state = ParsingState::Number(num*10 + current_number);
-                        i+=1;
-                    },
-                    None => {
-                        tokens.push(Tokens::Number(*num));
-                        return tokens;
-                    }
-                };
-            },
-            ParsingState::Symbol(sym) => {
-                 match current_char {
-                    Some(ch) if !letters_and_numbers.is_match(&ch) => {
-                        tokens.push(Tokens::Symbol(sym.clone()));
-                        current_state = ParsingState::Init;
-                    },
-                    Some(ch) => {
-                        let mut new_string = sym.clone();
-                        new_string.push_str(ch);
-                        current_state = ParsingState::Symbol(new_string);
-                        i+=1;
-                    },
-                    None => {
-                        tokens.push(Tokens::Symbol(sym.clone()));
-                        return tokens;
+            self.position.col += 1;
+        }
+        Some(ch)
+    }
+
+    fn next_if(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    // rest of a string after the opening `"`
+    fn string(&mut self, start: Position) -> Result<Cow<'a, str>, ParseError> {
+        let begin = self.offset();
+        let mut escaped: Option<String> = None;
+        loop {
+            let end = self.offset();
+            match self.next() {
+                Some('"') => return Ok(match escaped {
+                    Some(string) => Cow::Owned(string),
+                    None => Cow::Borrowed(&self.input[begin..end])
+                }),
+                Some('\\') => {
+                    let input = self.input;
+                    let string = escaped.get_or_insert_with(|| String::from(&input[begin..end]));
+                    match self.next() {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some(ch) => string.push(ch),
+                        None => return Err(ParseError::UnterminatedString(start))
                     }
-                };
+                },
+                Some(ch) => if let Some(string) = escaped.as_mut() {
+                    string.push(ch);
+                },
+                None => return Err(ParseError::UnterminatedString(start))
+            }
+        }
+    }
+
+    // rest of a block comment after the opening `#|`, block comments nest
+    fn block_comment(&mut self, start: Position) -> Result<(), ParseError> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next() {
+                Some('|') if self.next_if('#') => depth -= 1,
+                Some('#') if self.next_if('|') => depth += 1,
+                Some(_) => (),
+                None => return Err(ParseError::UnterminatedComment(start))
             }
         }
+        Ok(())
+    }
+}
+
+// anything that doesn't end a token, a token that is a number literal is a number
+pub fn is_symbol_char(ch: char) -> bool {
+    !ch.is_whitespace() && !"()'`\",;".contains(ch)
+}
+
+/**
+Optional sign followed by `0x`, `0b` or `0o` digits or by decimal digits with an optional exponent,
+`_` can separate digits. Returns `None` if `text` isn't a number literal.
+1_000 -> 1000
+-0xff -> -255
+2e3   -> 2000
+*/
+fn number(text: &str, start: Position) -> Option<Result<i64, ParseError>> {
+    let (negative, unsigned) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text)
+    };
+    let prefixed = |prefix: &str| unsigned.strip_prefix(prefix).or_else(|| unsigned.strip_prefix(&prefix.to_uppercase()));
+    let (radix, digits, exponent) = if let Some(digits) = prefixed("0x") {
+        (16, digits, None)
+    } else if let Some(digits) = prefixed("0b") {
+        (2, digits, None)
+    } else if let Some(digits) = prefixed("0o") {
+        (8, digits, None)
+    } else {
+        match unsigned.find(['e', 'E']) {
+            Some(i) => (10, &unsigned[..i], Some(&unsigned[i + 1..])),
+            None => (10, unsigned, None)
+        }
+    };
+    let is_digits = digits.chars().next().is_some_and(|ch| ch.is_digit(radix))
+        && digits.chars().all(|ch| ch == '_' || ch.is_digit(radix));
+    let exponent = match exponent.map(|e| e.strip_prefix('+').unwrap_or(e)) {
+        Some(e) if e.is_empty() || !e.bytes().all(|b| b.is_ascii_digit()) => return None,
+        Some(e) => Some(e),
+        None => None
+    };
+    if !is_digits {
+        return None;
+    }
+    let mut value: i128 = 0;
+    for digit in digits.chars().filter_map(|ch| ch.to_digit(radix)) {
+        value = match value.checked_mul(radix as i128).and_then(|v| v.checked_add(digit as i128)) {
+            Some(value) => value,
+            None => return Some(Err(ParseError::NumberOutOfRange(start)))
+        };
+    }
+    if let Some(exponent) = exponent {
+        let scaled = exponent.parse::<u32>().ok()
+            .and_then(|e| 10i128.checked_pow(e))
+            .and_then(|scale| value.checked_mul(scale));
+        value = match scaled {
+            Some(value) => value,
+            None => return Some(Err(ParseError::NumberOutOfRange(start)))
+        };
+    }
+    let value = if negative { -value } else { value };
+    Some(i64::try_from(value).map_err(|_| ParseError::NumberOutOfRange(start)))
+}
+
+// returns tokens together with the position where each of them starts
+pub fn parse_fsm(input: &str) -> Result<(Vec<Tokens<'_>>, Vec<Position>), ParseError> {
+    Ok(scan(input, false)?.into_iter().map(|(token, position, _)| (token, position)).unzip())
+}
+
+/// Tokens including comments, each with its position and the exact text it was read from
+pub fn parse_fsm_with_comments(input: &str) -> Result<Vec<(Tokens<'_>, Position, &str)>, ParseError> {
+    scan(input, true)
+}
+
+fn scan(input: &str, keep_comments: bool) -> Result<Vec<(Tokens<'_>, Position, &str)>, ParseError> {
+    let mut cursor = Cursor { input, chars: input.char_indices().peekable(), position: Position::default() };
+    let mut tokens = vec![];
+    loop {
+        let start = cursor.position;
+        let offset = cursor.offset();
+        let token = match cursor.next() {
+            Some(ch) if ch.is_whitespace() => continue,
+            Some('(') => Tokens::OP,
+            Some(')') => Tokens::CP,
+            Some('\'') => Tokens::Quote,
+            Some('`') => Tokens::Quasiquote,
+            Some(',') if cursor.next_if('@') => Tokens::UnquoteSplicing,
+            Some(',') => Tokens::Unquote,
+            Some(';') => {
+                while !matches!(cursor.peek(), Some('\n') | None) {
+                    cursor.next();
+                }
+                Tokens::Comment(input[offset..cursor.offset()].trim_end())
+            },
+            // `#!` line at the very start, so scripts can be executable
+            Some('#') if offset == 0 && cursor.next_if('!') => {
+                while !matches!(cursor.peek(), Some('\n') | None) {
+                    cursor.next();
+                }
+                Tokens::Comment(input[offset..cursor.offset()].trim_end())
+            },
+            Some('#') if cursor.next_if('|') => {
+                cursor.block_comment(start)?;
+                Tokens::Comment(&input[offset..cursor.offset()])
+            },
+            Some('#') if cursor.next_if('_') => Tokens::Discard,
+            Some('"') => Tokens::Str(cursor.string(start)?),
+            Some(_) => {
+                while cursor.peek().is_some_and(is_symbol_char) {
+                    cursor.next();
+                }
+                let text = &input[offset..cursor.offset()];
+                match number(text, start) {
+                    Some(n) => Tokens::Number(n?),
+                    None => Tokens::Symbol(text)
+                }
+            },
+            None => return Ok(tokens)
+        };
+        let text = match token {
+            Tokens::Comment(_) if !keep_comments => continue,
+            Tokens::Comment(comment) => comment,
+            _ => &input[offset..cursor.offset()]
+        };
+        tokens.push((token, start, text));
     }
 }
 
@@ -81,10 +245,89 @@ pub fn parse_fsm(input: &String) -> Vec<Tokens> {
 mod tests {
     use super::*;
 
+    fn tokens(input: &str) -> Vec<Tokens<'_>> {
+        parse_fsm(input).unwrap().0
+    }
+
     #[test]
     fn test_fsm() {
-        assert_eq!(parse_fsm(&String::from("1")), vec![Tokens::Number(1)]);
-        assert_eq!(parse_fsm(&String::from("2")), vec![Tokens::Number(2)]);
-        assert_eq!(parse_fsm(&String::from("(+ 1 2)")), vec![Tokens::OP, Tokens::Symbol(String::from("+")), Tokens::Number(1), Tokens::Number(2), Tokens::CP]);
+        assert_eq!(tokens("1"), vec![Tokens::Number(1)]);
+        assert_eq!(tokens("2"), vec![Tokens::Number(2)]);
+        assert_eq!(tokens("(+ 1 2)"), vec![Tokens::OP, Tokens::Symbol("+"), Tokens::Number(1), Tokens::Number(2), Tokens::CP]);
+        assert_eq!(tokens("`(a ,b ,@c 'd)"), vec![Tokens::Quasiquote, Tokens::OP, Tokens::Symbol("a"),
+                                                 Tokens::Unquote, Tokens::Symbol("b"),
+                                                 Tokens::UnquoteSplicing, Tokens::Symbol("c"),
+                                                 Tokens::Quote, Tokens::Symbol("d"), Tokens::CP]);
+        assert_eq!(tokens(r#"(a "b c" "\"d\"\n")"#), vec![Tokens::OP, Tokens::Symbol("a"),
+                                                        Tokens::Str(Cow::from("b c")),
+                                                        Tokens::Str(Cow::from("\"d\"\n")), Tokens::CP]);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbol = Tokens::Symbol;
+        assert_eq!(tokens("(empty? <= *debug* str->int a+b 1+ 2nd x# + %)"),
+                   vec![Tokens::OP, symbol("empty?"), symbol("<="), symbol("*debug*"), symbol("str->int"), symbol("a+b"),
+                        symbol("1+"), symbol("2nd"), symbol("x#"), symbol("+"), symbol("%"), Tokens::CP]);
+        assert_eq!(tokens("a'b`c,d;e"), vec![symbol("a"), Tokens::Quote, symbol("b"), Tokens::Quasiquote, symbol("c"),
+                                             Tokens::Unquote, symbol("d")]);
+        assert_eq!(tokens("12)"), vec![Tokens::Number(12), Tokens::CP]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_numbers() {
+        let numbers = tokens("0xff 0XfF -0x10 0b1010 0o17 1_000_000 -5 +5 2e3 1E+2 007");
+        assert_eq!(numbers, [255, 255, -16, 10, 15, 1_000_000, -5, 5, 2000, 100, 7].iter().map(|n| Tokens::Number(*n)).collect::<Vec<_>>());
+        assert_eq!(tokens("- -a 1e 1e-3 0x 0b2 _1 1.5"),
+                   vec![Tokens::Symbol("-"), Tokens::Symbol("-a"), Tokens::Symbol("1e"), Tokens::Symbol("1e-3"), Tokens::Symbol("0x"),
+                        Tokens::Symbol("0b2"), Tokens::Symbol("_1"), Tokens::Symbol("1.5")]);
+        assert_eq!(tokens("9223372036854775807 -9223372036854775808"), vec![Tokens::Number(i64::MAX), Tokens::Number(i64::MIN)]);
+        let out_of_range = Err(ParseError::NumberOutOfRange(Position { line: 1, col: 4 }));
+        assert_eq!(parse_fsm("(a 9223372036854775808)"), out_of_range);
+        assert_eq!(parse_fsm("(a 99999999999999999999999999999999999999999)"), out_of_range);
+        assert_eq!(parse_fsm("(a 1e40)"), out_of_range);
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(tokens("(λ café \"ünï\\tcödé\" 日本)"), vec![Tokens::OP, Tokens::Symbol("λ"), Tokens::Symbol("café"),
+                                                              Tokens::Str(Cow::from("ünï\tcödé")), Tokens::Symbol("日本"), Tokens::CP]);
+        assert_eq!(parse_fsm("λ é").unwrap().1, vec![Position { line: 1, col: 1 }, Position { line: 1, col: 3 }]);
+    }
+
+    #[test]
+    fn test_borrowed() {
+        assert!(matches!(&tokens("\"plain\"")[0], Tokens::Str(Cow::Borrowed("plain"))));
+        assert!(matches!(&tokens("\"a\\nb\"")[0], Tokens::Str(Cow::Owned(_))));
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(tokens("(a ; comment (\n b) ;"), vec![Tokens::OP, Tokens::Symbol("a"), Tokens::Symbol("b"), Tokens::CP]);
+        assert_eq!(tokens("a #| block #| nested |# ) |# b"), vec![Tokens::Symbol("a"), Tokens::Symbol("b")]);
+        assert_eq!(tokens("#!/usr/bin/env lesp\na"), vec![Tokens::Symbol("a")]);
+        assert_eq!(tokens("a #!b"), vec![Tokens::Symbol("a"), Tokens::Symbol("#!b")]);
+        assert_eq!(tokens("#_(a) b;c"), vec![Tokens::Discard, Tokens::OP, Tokens::Symbol("a"), Tokens::CP, Tokens::Symbol("b")]);
+        let source = parse_fsm_with_comments("#!lesp\r\n(a ;; one\n #| two |# 0xff)").unwrap();
+        assert_eq!(source.iter().map(|(token, _, _)| token.clone()).collect::<Vec<_>>(),
+                   vec![Tokens::Comment("#!lesp"), Tokens::OP, Tokens::Symbol("a"), Tokens::Comment(";; one"),
+                        Tokens::Comment("#| two |#"), Tokens::Number(255), Tokens::CP]);
+        assert_eq!(source.iter().map(|(_, _, text)| *text).collect::<Vec<_>>(),
+                   vec!["#!lesp", "(", "a", ";; one", "#| two |#", "0xff", ")"]);
+    }
+
+    #[test]
+    fn test_unterminated() {
+        assert_eq!(parse_fsm("(a\n \"bc"), Err(ParseError::UnterminatedString(Position { line: 2, col: 2 })));
+        assert_eq!(parse_fsm("\"bc\\"), Err(ParseError::UnterminatedString(Position { line: 1, col: 1 })));
+        assert_eq!(parse_fsm("a #| #| |#"), Err(ParseError::UnterminatedComment(Position { line: 1, col: 3 })));
+    }
+
+    #[test]
+    fn test_positions() {
+        let (tokens, positions) = parse_fsm("(a 12\n  \"s\" 'b)").unwrap();
+        assert_eq!(tokens.len(), positions.len());
+        let at = |line, col| Position { line, col };
+        assert_eq!(positions, vec![at(1, 1), at(1, 2), at(1, 4), at(2, 3), at(2, 7), at(2, 8), at(2, 9)]);
+    }
+}
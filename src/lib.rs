@@ -0,0 +1,47 @@
+//! lesp, a small Lisp that can be embedded through `Interpreter`
+
+mod lexer;
+mod lisp;
+mod parser;
+mod built_in;
+mod namespace;
+mod pretty;
+mod interpreter;
+mod convert;
+
+pub use convert::{FromLisp, IntoLisp, IntoLispResult, NativeFn};
+pub use interpreter::{Error, Function, Interpreter, Value};
+pub use lisp::Arity;
+pub use pretty::WIDTH;
+
+/// True when more input could make `input` readable, e.g. it has an unclosed `(` or string
+pub fn is_incomplete(input: &str) -> bool {
+    matches!(parser::read(input), Err(e) if e.is_incomplete())
+}
+
+/// Byte offset where the symbol at the end of `input` starts, e.g. to complete it, `input.len()` when there is none
+pub fn symbol_start(input: &str) -> usize {
+    input.char_indices().rev()
+        .take_while(|(_, ch)| lexer::is_symbol_char(*ch))
+        .last()
+        .map_or(input.len(), |(i, _)| i)
+}
+
+/// Reformats source code within `width` columns keeping comments, errors point to `source`
+pub fn format_source(input: &str, source: &str, width: usize) -> Result<String, Error> {
+    pretty::format_source(input, width).map_err(|e| Error::from(lisp::EvalError::parse(&e, source)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_start() {
+        assert_eq!(symbol_start("(map xs sq"), 8);
+        assert_eq!(symbol_start("(geometry/per"), 1);
+        assert_eq!(symbol_start("(λé"), 1);
+        assert_eq!(symbol_start("(car "), 5);
+        assert_eq!(symbol_start(""), 0);
+    }
+}
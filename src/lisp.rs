@@ -1,13 +1,13 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Formatter;
-use std::fmt::Error;
 use std::fmt;
 use std::rc::Rc;
 use core::ops::Deref;
 
-use crate::lexer;
-use crate::parser;
-use crate::built_in;
+use crate::lexer::Position;
+use crate::namespace;
+use crate::parser::{self, ParseError, SourceMap};
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Function {
@@ -21,170 +21,722 @@ pub struct Function {
 
 #[derive(Clone)]
 pub enum FunctionType {
-    Native(String, NativeFunction),
+    /// Gets its arguments evaluated
+    Native(Rc<Native>),
+    /// Special form, gets its arguments as they are written and evaluates them itself
+    Special(Rc<Native>),
     UserDefined(Rc<Function>)
 }
-pub type NativeFunction = fn(&mut Context, &[Type]) -> Type;
+pub type NativeFunction = Rc<dyn Fn(&mut Context, &[Type]) -> Result<Type, EvalError>>;
+
+/// Function implemented in Rust, either built in or registered by the host
+pub struct Native {
+    pub name: String,
+    pub arity: Arity,
+    pub doc: String,
+    pub f: NativeFunction
+}
+
+/// Number of arguments a native accepts, checked before it's called
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Arity {
+    pub min: usize,
+    /// `None` when any number of arguments past `min` is accepted
+    pub max: Option<usize>
+}
+
+impl Arity {
+    pub fn exactly(n: usize) -> Arity {
+        Arity { min: n, max: Some(n) }
+    }
+
+    pub fn at_least(n: usize) -> Arity {
+        Arity { min: n, max: None }
+    }
+
+    pub fn between(min: usize, max: usize) -> Arity {
+        Arity { min, max: Some(max) }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+/// `2`, `0 to 1` or `1 or more`
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "{} or more", self.min)
+        }
+    }
+}
 pub type Context = HashMap<String, Type>;
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone)]
 pub enum Type {
-    Symbol(String), Bool(bool), Number(u32), List(Vec<Type>), Function(FunctionType)
+    Nil, Symbol(String), Str(String), Bool(bool), Number(i64), List(Vec<Type>), Function(FunctionType),
+    /// Form tagged with where it was read from, only produced by expansion of source code.
+    /// Ignored by equality and printing.
+    Located(Box<Type>, Rc<Location>)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Location {
+    pub source: Rc<str>,
+    pub line: usize,
+    pub col: usize
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.col)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frame {
+    pub name: String,
+    /// Call site of the function
+    pub location: Option<Location>
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ErrorKind {
+    /// Value passed to `throw`
+    Thrown(Type),
+    /// Error raised by the interpreter itself, e.g. unknown symbol or wrong argument type
+    Runtime(String)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct EvalError {
+    pub kind: ErrorKind,
+    /// Where the error was raised, if it happened in code read from source
+    pub location: Option<Location>,
+    /// Functions the error unwound through, innermost first
+    pub stack: Vec<Frame>
+}
+
+impl EvalError {
+    pub fn runtime<S: Into<String>>(message: S) -> EvalError {
+        EvalError { kind: ErrorKind::Runtime(message.into()), location: None, stack: vec![] }
+    }
+
+    pub fn thrown(value: Type) -> EvalError {
+        EvalError { kind: ErrorKind::Thrown(value), location: None, stack: vec![] }
+    }
+
+    /// Reader error located in `source`
+    pub fn parse(error: &ParseError, source: &str) -> EvalError {
+        let position = error.position();
+        let location = Location { source: Rc::from(source), line: position.line, col: position.col };
+        EvalError { location: Some(location), ..EvalError::runtime(error.to_string()) }
+    }
+
+    fn with_frame(mut self, name: &str) -> EvalError {
+        self.stack.push(Frame { name: String::from(name), location: None });
+        self
+    }
+
+    // innermost located form is where the error happened, the first one outside a frame is its call site
+    fn at(mut self, location: &Location) -> EvalError {
+        if self.location.is_none() {
+            self.location = Some(location.clone());
+        }
+        if let Some(frame) = self.stack.last_mut() {
+            if frame.location.is_none() {
+                frame.location = Some(location.clone());
+            }
+        }
+        self
+    }
+
+    /// Value bound by `catch`, interpreter errors are turned into `ex-info`,
+    /// thrown `ex-info` gets the stack filled in unless it already has one
+    pub fn to_value(&self) -> Type {
+        let stack = Type::List(self.stack.iter().map(|frame| Type::Str(frame.to_string())).collect());
+        match &self.kind {
+            ErrorKind::Thrown(Type::List(elems)) if is_ex_info(elems) && elems[3] == Type::Nil => {
+                ex_info(elems[1].clone(), elems[2].clone(), stack)
+            },
+            ErrorKind::Thrown(value) => value.clone(),
+            ErrorKind::Runtime(message) => ex_info(Type::Str(message.clone()), Type::Nil, stack)
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Thrown(value) => write!(f, "Uncaught {}", value)?,
+            ErrorKind::Runtime(message) => f.write_str(message)?
+        }
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        for frame in &self.stack {
+            write!(f, "\n    at {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+(ex-info "message" data)
+-> (ex-info "message" data stack)
+*/
+pub fn ex_info(message: Type, data: Type, stack: Type) -> Type {
+    Type::List(vec![Type::Symbol(String::from("ex-info")), message, data, stack])
+}
+
+pub fn is_ex_info(elems: &[Type]) -> bool {
+    elems.len() == 4 && elems[0] == Type::Symbol(String::from("ex-info"))
 }
 
 impl PartialEq for FunctionType {
     fn eq(&self, other: &FunctionType) -> bool {
         match (self, other) {
             (FunctionType::UserDefined(f), FunctionType::UserDefined(f_other)) => f.eq(f_other),
-            (FunctionType::Native(n, f), FunctionType::Native(n_other, f_other)) => n.eq(n_other) && (f as *const _ == f_other as *const _),
+            (FunctionType::Native(native), FunctionType::Native(native_other)) => Rc::ptr_eq(native, native_other),
+            (FunctionType::Special(native), FunctionType::Special(native_other)) => Rc::ptr_eq(native, native_other),
             (_,_) => false
         }
     }
 }
 
 impl fmt::Debug for FunctionType {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            FunctionType::Native(name, _) => format!("native({})", name).fmt(f),
+            FunctionType::Native(native) => format!("native({})", native.name).fmt(f),
+            FunctionType::Special(native) => format!("special({})", native.name).fmt(f),
             FunctionType::UserDefined(fun) => fun.name.fmt(f)
         }
     }
 }
 
+impl PartialEq for Type {
+    fn eq(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Located(form, _), other) | (other, Type::Located(form, _)) => form.deref() == other,
+            (Type::Nil, Type::Nil) => true,
+            (Type::Symbol(s), Type::Symbol(s_other)) => s == s_other,
+            (Type::Str(s), Type::Str(s_other)) => s == s_other,
+            (Type::Bool(b), Type::Bool(b_other)) => b == b_other,
+            (Type::Number(n), Type::Number(n_other)) => n == n_other,
+            (Type::List(elems), Type::List(elems_other)) => elems == elems_other,
+            (Type::Function(f), Type::Function(f_other)) => f == f_other,
+            (_, _) => false
+        }
+    }
+}
+
+impl fmt::Display for FunctionType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FunctionType::Native(native) => write!(f, "#<native {}>", native.name),
+            FunctionType::Special(native) => write!(f, "#<special {}>", native.name),
+            FunctionType::UserDefined(fun) if fun.is_macro => write!(f, "#<macro {}>", fun.name),
+            FunctionType::UserDefined(fun) => write!(f, "#<fn {}>", fun.name)
+        }
+    }
+}
+
+/// Prints values the way they are written, so data reads back as the same value.
+/// Functions print as `#<fn name>` and can't be read back
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Type::Function(ft) => ft.fmt(f),
+            Type::Nil => f.write_str("nil"),
+            Type::List(elems) => {
+                f.write_str("(")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    elem.fmt(f)?;
+                }
+                f.write_str(")")
+            },
+            Type::Number(n) => n.fmt(f),
+            Type::Symbol(s) => f.write_str(s),
+            Type::Str(s) => {
+                f.write_str("\"")?;
+                for ch in s.chars() {
+                    match ch {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        _ => write!(f, "{}", ch)?
+                    }
+                }
+                f.write_str("\"")
+            },
+            Type::Bool(b) => b.fmt(f),
+            Type::Located(form, _) => form.fmt(f)
+        }
+    }
+}
+
 impl fmt::Debug for Type {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Type::Function(ft) => ft.fmt(f),
+            Type::Nil => f.write_str("nil"),
             Type::List(elems) => elems.fmt(f),
             Type::Number(n) => n.fmt(f),
             Type::Symbol(s) => s.fmt(f),
-            Type::Bool(b) => b.fmt(f)
+            Type::Str(s) => s.fmt(f),
+            Type::Bool(b) => b.fmt(f),
+            Type::Located(form, _) => form.fmt(f)
         }
     }
 }
 
-//TODO decouple allowing macroexpand-1
+impl Native {
+    fn invoke(&self, context: &mut Context, args: &[Type]) -> Result<Type, EvalError> {
+        if !self.arity.accepts(args.len()) {
+            return Err(EvalError::runtime(format!("Wrong number of arguments to {} -> expected {}, got {}",
+                                                  self.name, self.arity, args.len())));
+        }
+        (self.f)(context, args)
+    }
+}
+
 impl FunctionType {
-    pub fn eval(&self, mut context: &mut Context, args: &[Type]) -> Type {
+    /// Calls the function with `args` as they are written in the call form
+    pub fn eval(&self, context: &mut Context, args: &[Type]) -> Result<Type, EvalError> {
         match self {
-            FunctionType::Native(_, f) => {
-                f(&mut context, args)
+            // special forms are syntax, they don't show up in stack traces
+            FunctionType::Special(native) => native.invoke(context, args),
+            FunctionType::Native(native) => {
+                let args = eval_forms(args, context).map_err(|e| e.with_frame(&native.name))?;
+                native.invoke(context, &args).map_err(|e| e.with_frame(&native.name))
+            },
+            FunctionType::UserDefined(f_struct) if f_struct.is_macro => {
+                // macros are expanded before evaluation, this is only reached by macros
+                // that weren't known at expansion time or are passed around as values
+                let expanded = self.expand(context, args)?;
+                macroexpand_all(&expanded, context)?.eval(context)
             },
             FunctionType::UserDefined(f_struct) => {
-                let Function {
-                    context: captured_context, 
-                    name: f_name, 
-                    args: argument_bindings, 
-                    body, 
-                    is_macro, 
-                    vararg
-                } = f_struct.deref();
-                // TODO speed up clone
-                let mut current_context = if *is_macro {
-                    context.clone()
-                } else {
-                    captured_context.clone()
-                };
-                // assert_eq!(args.len(), argument_bindings.len(), "argument size mismatch {:?} -> {:?}", &args, &argument_bindings);
-                for i in 0..argument_bindings.len() {
-                    let arg_name = if let Type::Symbol(name) = argument_bindings.get(i).unwrap() {
-                        name
-                    } else { panic!() };
-                    let arg = if *is_macro {
-                        args.get(i).unwrap().clone() //eval function args first with current lexical scope
-                    } else {
-                        args.get(i).unwrap().eval(&mut context) //macro arg should not be avaluated
-                    };
-                    current_context.insert(arg_name.clone(), arg);
-                    current_context.insert(f_name.clone(), Type::Function(self.clone())); //named lambdas
-                }
-
-                match vararg {
-                    Some(Type::Symbol(name)) => {
-                        let from = argument_bindings.len();
-                        let to = args.len();
-                        let varargs = if *is_macro {
-                            args[from..to].to_vec()
-                        } else {
-                            args[from..to].into_iter().map(|a| a.eval(&mut context)).collect()
-                        };
-                        current_context.insert(name.clone(), Type::List(varargs));
-                    },
-                    None => (),
-                    _ => unreachable!()
-                }
+                //eval function args first with current lexical scope
+                let args = eval_forms(args, context).map_err(|e| e.with_frame(&f_struct.name))?;
+                self.call(context, args)
+            }
+        }
+    }
 
-                let result = eval_forms(&body, &mut current_context);
-                if *is_macro {
-                    eval_forms(&result, &mut context)
-                } else {
-                    result
-                }.last().unwrap().clone()
+    /// Calls the function with already evaluated `args`, macros take them as forms
+    pub fn call(&self, context: &mut Context, args: Vec<Type>) -> Result<Type, EvalError> {
+        match self {
+            FunctionType::Special(native) => {
+                Err(EvalError::runtime(format!("Special form can't be applied -> {}", native.name)))
+            },
+            FunctionType::Native(native) => {
+                native.invoke(context, &args).map_err(|e| e.with_frame(&native.name))
+            },
+            FunctionType::UserDefined(f_struct) if f_struct.is_macro => self.eval(context, &args),
+            FunctionType::UserDefined(f_struct) => {
+                let mut current_context = self.bind_arguments(context, args).map_err(|e| e.with_frame(&f_struct.name))?;
+                let result = eval_forms(&f_struct.body, &mut current_context).map_err(|e| e.with_frame(&f_struct.name))?;
+                Ok(result.last().cloned().unwrap_or(Type::Nil))
             }
         }
     }
+
+    /// Runs macro body against unevaluated `args` and returns produced form without evaluating it
+    pub fn expand(&self, context: &mut Context, args: &[Type]) -> Result<Type, EvalError> {
+        match self {
+            FunctionType::UserDefined(f_struct) if f_struct.is_macro => {
+                //macro args are not evaluated
+                let args = args.iter().map(strip_locations).collect();
+                let mut current_context = self.bind_arguments(context, args).map_err(|e| e.with_frame(&f_struct.name))?;
+                let result = eval_forms(&f_struct.body, &mut current_context).map_err(|e| e.with_frame(&f_struct.name))?;
+                Ok(result.last().cloned().unwrap_or(Type::Nil))
+            },
+            _ => Err(EvalError::runtime(format!("Macro expected -> {}", self)))
+        }
+    }
+
+    pub fn is_macro(&self) -> bool {
+        match self {
+            FunctionType::UserDefined(f_struct) => f_struct.is_macro,
+            FunctionType::Native(_) | FunctionType::Special(_) => false
+        }
+    }
+
+    fn bind_arguments(&self, context: &Context, args: Vec<Type>) -> Result<Context, EvalError> {
+        let f_struct = if let FunctionType::UserDefined(f_struct) = self {
+            f_struct
+        } else { unreachable!() };
+        let Function {
+            context: captured_context,
+            name: f_name,
+            args: argument_bindings,
+            is_macro,
+            vararg,
+            ..
+        } = f_struct.deref();
+        if args.len() < argument_bindings.len() || (vararg.is_none() && args.len() > argument_bindings.len()) {
+            return Err(EvalError::runtime(format!("Wrong number of arguments to {} -> expected {}, got {}",
+                                                  f_name, argument_bindings.len(), args.len())));
+        }
+        // TODO speed up clone
+        let mut current_context = if *is_macro {
+            context.clone()
+        } else {
+            captured_context.clone()
+        };
+        current_context.insert(f_name.clone(), Type::Function(self.clone())); //named lambdas
+        let mut args = args.into_iter();
+        for (binding, arg) in argument_bindings.iter().zip(args.by_ref()) {
+            let arg_name = if let Type::Symbol(name) = binding {
+                name
+            } else { unreachable!() };
+            current_context.insert(arg_name.clone(), arg);
+        }
+
+        match vararg {
+            Some(Type::Symbol(name)) => {
+                current_context.insert(name.clone(), Type::List(args.collect()));
+            },
+            None => (),
+            _ => unreachable!()
+        }
+        Ok(current_context)
+    }
 }
 
-fn eval_forms(forms: &[Type], mut ctx: &mut Context) -> Vec<Type> {
+fn eval_forms(forms: &[Type], ctx: &mut Context) -> Result<Vec<Type>, EvalError> {
     let mut result = vec![];
-    for form in &forms[..] {
-        result.push(form.eval(&mut ctx));
+    for form in forms {
+        result.push(form.eval(ctx)?);
     }
-    result
+    Ok(result)
 }
 
 impl Type {
-    pub fn eval(&self, mut context: &mut Context) -> Type {
+    pub fn eval(&self, context: &mut Context) -> Result<Type, EvalError> {
         match self {
+            Type::List(elems) if elems.is_empty() => {
+                Err(EvalError::runtime("Cannot evaluate empty list"))
+            },
             Type::List(elems) => {
-                let symbol = elems.get(0).unwrap().eval(&mut context);
+                let symbol = elems[0].eval(context)?;
                 if let Type::Function(f) = symbol  {
-                    f.eval(&mut context, &elems[1..])
+                    f.eval(context, &elems[1..])
                 } else {
-                    panic!("function expected as first argument {:?}", symbol)
+                    Err(EvalError::runtime(format!("function expected as first argument {}", symbol)))
                 }
             },
-            Type::Number(_n) => self.clone(), // evaluates to itself
-            Type::Bool(_b) => self.clone(), // evaluates to itself
-            Type::Symbol(name) => {
-                context.get(name).expect(format!("Symbol not found -> {:?}", name).as_str()).clone()
+            Type::Nil => Ok(Type::Nil), // evaluates to itself
+            Type::Number(_n) => Ok(self.clone()), // evaluates to itself
+            Type::Bool(_b) => Ok(self.clone()), // evaluates to itself
+            Type::Str(_s) => Ok(self.clone()), // evaluates to itself
+            Type::Symbol(name) => match context.get(name) {
+                Some(value) => Ok(value.clone()),
+                None => namespace::resolve(context, name)
             },
-            _ => unimplemented!()
+            Type::Function(_) => Ok(self.clone()), // evaluates to itself
+            Type::Located(form, location) => form.eval(context).map_err(|e| e.at(location))
         }
     }
 }
 
-pub fn eval_in_context(input: &String, mut context: &mut Context) -> Type {
-    if input.is_empty() {
-        return Type::List(vec![]); // empty list is nil
+/// Form without `Type::Located` wrappers, macros see their arguments as plain data
+pub fn strip_locations(form: &Type) -> Type {
+    match form {
+        Type::Located(form, _) => strip_locations(form),
+        Type::List(elems) => Type::List(elems.iter().map(strip_locations).collect()),
+        _ => form.clone()
+    }
+}
+
+fn head_symbol(elems: &[Type]) -> Option<&str> {
+    match elems.first() {
+        Some(Type::Symbol(s)) => Some(s.as_str()),
+        _ => None
+    }
+}
+
+/// Expands `form` once if it is a macro call, returns `None` otherwise
+pub fn macroexpand_1(form: &Type, context: &mut Context) -> Result<Option<Type>, EvalError> {
+    let elems = if let Type::List(elems) = form {
+        elems
+    } else {
+        return Ok(None);
+    };
+    // qualified heads go through aliases the way evaluation does
+    let head = head_symbol(elems).and_then(|name| context.get(name).cloned().or_else(|| namespace::resolve(context, name).ok()));
+    let f = match head {
+        Some(Type::Function(f)) if f.is_macro() => f,
+        _ => return Ok(None)
+    };
+    f.expand(context, &elems[1..]).map(Some)
+}
+
+/// Expands `form` until it is no longer a macro call
+pub fn macroexpand(form: &Type, context: &mut Context) -> Result<Type, EvalError> {
+    let mut form = form.clone();
+    while let Some(expanded) = macroexpand_1(&form, context)? {
+        form = expanded;
+    }
+    Ok(form)
+}
+
+/// Expands `form` and all of its subforms, leaving quoted data untouched
+pub fn macroexpand_all(form: &Type, context: &mut Context) -> Result<Type, EvalError> {
+    expand_all(form, context, None, None, &mut None, &mut vec![])
+}
+
+/// Where the forms being expanded were read from
+struct Origin {
+    source: Rc<str>,
+    // forms written in the source that macros may copy into their expansions
+    known: Vec<(Type, SourceMap)>
+}
+
+impl Origin {
+    fn find(&self, form: &Type) -> Option<SourceMap> {
+        self.known.iter().find(|(known, _)| known == form).map(|(_, map)| map.clone())
+    }
+
+    fn location(&self, position: Position) -> Location {
+        Location { source: self.source.clone(), line: position.line, col: position.col }
+    }
+}
+
+// names bound by symbols among `params`, `rest...` binds `rest`
+fn bound_names(params: &[Type]) -> impl Iterator<Item = String> + '_ {
+    params.iter().filter_map(|param| match param {
+        Type::Symbol(name) => Some(String::from(name.strip_suffix("...").unwrap_or(name))),
+        _ => None
+    })
+}
+
+// with an origin every list in evaluation position is wrapped in `Type::Located`,
+// forms produced by macros are located at the user written form they were copied from
+// or at the enclosing form.
+// `locals` are names bound by enclosing `fn`, `let` and `catch`, a call of a local isn't a macro call
+fn expand_all(form: &Type, context: &mut Context, map: Option<&SourceMap>, parent: Option<Position>,
+              origin: &mut Option<Origin>, locals: &mut Vec<String>) -> Result<Type, EvalError> {
+    let found;
+    let map = match (map, origin.as_ref()) {
+        (None, Some(origin)) => {
+            found = origin.find(form).or_else(|| parent.map(|position| SourceMap { position, children: vec![] }));
+            found.as_ref()
+        },
+        _ => map
+    };
+    let parent = map.map(|map| map.position);
+    let is_local = |elems: &[Type]| head_symbol(elems).is_some_and(|name| locals.iter().any(|local| local == name));
+    let expansion_map;
+    let expanded = match form {
+        Type::List(elems) if is_local(elems) => None,
+        _ => macroexpand_1(form, context)?
+    };
+    let (form, map) = match expanded {
+        Some(expanded) => {
+            if let (Some(origin), Some(map), Type::List(elems)) = (origin.as_mut(), map, form) {
+                let args = elems.iter().cloned().zip(map.children.iter().cloned()).skip(1);
+                origin.known.extend(args);
+            }
+            // the expansion is located at the macro call
+            expansion_map = map.map(|map| SourceMap { position: map.position, children: vec![] });
+            (Cow::Owned(macroexpand(&expanded, context)?), expansion_map.as_ref())
+        },
+        // borrowed, copying every subtree on the way down costs depth times size
+        None => (Cow::Borrowed(form), map)
+    };
+    let elems = match form.as_ref() {
+        Type::List(elems) => elems,
+        Type::Symbol(name) => {
+            return match (namespace::check_access(context, name), origin.as_ref(), map) {
+                (Err(e), Some(origin), Some(map)) => Err(e.at(&origin.location(map.position))),
+                (result, _, _) => result.map(|_| form.into_owned())
+            };
+        },
+        _ => return Ok(form.into_owned())
+    };
+    let child = |i: usize| map.and_then(|map| map.children.get(i));
+    let head = if is_local(elems) { None } else { head_symbol(elems) };
+    let scope = locals.len();
+    let keep = match head {
+        Some("quote") => return Ok(locate(form.as_ref().clone(), map, origin)),
+        Some("quasiquote") => {
+            let expanded = macroexpand_template(form.as_ref(), context, 0, map, origin, locals)?;
+            return Ok(locate(expanded, map, origin));
+        },
+        Some("let") => {
+            let bindings = if let Some(Type::List(bindings)) = elems.get(1) {
+                bindings
+            } else {
+                return Ok(form.as_ref().clone());
+            };
+            let mut expanded_bindings = vec![];
+            for (i, binding) in bindings.iter().enumerate() {
+                expanded_bindings.push(match binding {
+                    Type::List(pair) if pair.len() > 1 => {
+                        let value_map = child(1).and_then(|m| m.children.get(i)).and_then(|m| m.children.get(1));
+                        let value = expand_all(&pair[1], context, value_map, parent, origin, locals)?;
+                        // later bindings and the body see the name
+                        locals.extend(bound_names(&pair[..1]));
+                        Type::List(vec![pair[0].clone(), value])
+                    },
+                    _ => binding.clone()
+                });
+            }
+            let mut result = vec![elems[0].clone(), Type::List(expanded_bindings)];
+            for (i, elem) in elems.iter().enumerate().skip(2) {
+                result.push(expand_all(elem, context, child(i), parent, origin, locals)?);
+            }
+            locals.truncate(scope);
+            return Ok(locate(Type::List(result), map, origin));
+        },
+        Some("try") => {
+            let mut result = vec![elems[0].clone()];
+            for (i, elem) in elems.iter().enumerate().skip(1) {
+                let clause_keep = match elem {
+                    Type::List(clause) => match head_symbol(clause) {
+                        Some("catch") => 2, // binding
+                        Some("finally") => 1,
+                        _ => 0
+                    },
+                    _ => 0
+                };
+                result.push(match elem {
+                    Type::List(clause) if clause_keep > 0 => {
+                        let clause_map = child(i);
+                        let keep = clause_keep.min(clause.len());
+                        let mut expanded = clause[..keep].to_vec();
+                        if clause_keep == 2 {
+                            locals.extend(bound_names(&clause[1..keep]));
+                        }
+                        for (j, form) in clause.iter().enumerate().skip(keep) {
+                            expanded.push(expand_all(form, context, clause_map.and_then(|m| m.children.get(j)), parent, origin, locals)?);
+                        }
+                        locals.truncate(scope);
+                        Type::List(expanded)
+                    },
+                    _ => expand_all(elem, context, child(i), parent, origin, locals)?
+                });
+            }
+            return Ok(locate(Type::List(result), map, origin));
+        },
+        Some("fn") | Some("macro") | Some("hmacro") => {
+            locals.extend(bound_names(&elems[1..elems.len().min(2)]));
+            if let Some(Type::List(params)) = elems.get(2) {
+                locals.extend(bound_names(params));
+            }
+            3 // name and argument list
+        },
+        Some("def") | Some("def-") => 2,
+        // library and namespace names and `require` options are data
+        Some("require") | Some("ns") | Some("in-ns") => elems.len(),
+        _ => 0
+    };
+    let keep = keep.min(elems.len());
+    let mut result = elems[..keep].to_vec();
+    for (i, elem) in elems.iter().enumerate().skip(keep) {
+        result.push(expand_all(elem, context, child(i), parent, origin, locals)?);
     }
-    let res = lexer::parse_fsm(&input);
-    let (n, _) = parser::build(&res, 0);
+    locals.truncate(scope);
+    Ok(locate(Type::List(result), map, origin))
+}
+
+fn locate(form: Type, map: Option<&SourceMap>, origin: &Option<Origin>) -> Type {
+    match (map, origin) {
+        (Some(map), Some(origin)) => Type::Located(Box::new(form), Rc::new(origin.location(map.position))),
+        _ => form
+    }
+}
+
+// only unquoted parts of a quasiquote template are code
+fn macroexpand_template(form: &Type, context: &mut Context, depth: usize, map: Option<&SourceMap>,
+                        origin: &mut Option<Origin>, locals: &mut Vec<String>) -> Result<Type, EvalError> {
+    let elems = if let Type::List(elems) = form {
+        elems
+    } else {
+        return Ok(form.clone());
+    };
+    let child = |i: usize| map.and_then(|map| map.children.get(i));
+    let parent = map.map(|map| map.position);
+    match head_symbol(elems) {
+        Some("unquote") | Some("unquote-splicing") if elems.len() > 1 => {
+            let arg = if depth == 1 {
+                expand_all(&elems[1], context, child(1), parent, origin, locals)?
+            } else {
+                macroexpand_template(&elems[1], context, depth - 1, child(1), origin, locals)?
+            };
+            Ok(Type::List(vec![elems[0].clone(), arg]))
+        },
+        Some("quasiquote") if elems.len() > 1 => {
+            Ok(Type::List(vec![elems[0].clone(), macroexpand_template(&elems[1], context, depth + 1, child(1), origin, locals)?]))
+        },
+        _ => {
+            let mut result = vec![];
+            for (i, elem) in elems.iter().enumerate() {
+                result.push(macroexpand_template(elem, context, depth, child(i), origin, locals)?);
+            }
+            Ok(Type::List(result))
+        }
+    }
+}
+
+/// Evaluates `input` read from `source`, errors point to `source:line:col`
+pub fn eval_source(input: &str, source: &str, context: &mut Context) -> Result<Type, EvalError> {
+    let (n, maps) = parser::read(input).map_err(|e| EvalError::parse(&e, source))?;
 
     // execute all forms and return result from last form
-    let len = &n.len();
-    let butlast = len - 1;
-    for form in &n[..butlast] {
-        form.eval(&mut context);
+    // every form is expanded right before it's evaluated so it can use macros defined by previous forms
+    let mut origin = Some(Origin { source: Rc::from(source), known: vec![] });
+    let mut result = Type::Nil;
+    for (form, map) in n.iter().zip(&maps) {
+        if let Some(origin) = origin.as_mut() {
+            origin.known.clear();
+        }
+        result = expand_all(form, context, Some(map), None, &mut origin, &mut vec![])?.eval(context)?;
     }
-    n.get(len - 1).unwrap().eval(&mut context)
+    Ok(result)
+}
+
+#[cfg(test)]
+pub fn eval_in_context(input: &str, context: &mut Context) -> Result<Type, EvalError> {
+    eval_source(input, "<input>", context)
 }
 
-pub fn eval(input: &String) -> Type {
-    let mut context = built_in::init_context();
-    eval_in_context(&input, &mut context)
+#[cfg(test)]
+pub fn eval(input: &str) -> Type {
+    let mut context = crate::built_in::init_context();
+    eval_in_context(input, &mut context).unwrap()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::built_in;
 
-    pub fn bootstrap_and_eval(input: &String) -> Type {
+    pub fn bootstrap_and_eval(input: &str) -> Type {
         let mut context = built_in::init_context();
         let bytes = include_bytes!("../res/init.lisp");
         let init_str = String::from_utf8_lossy(bytes).to_string();
-        eval_in_context(&init_str, &mut context);
-        eval_in_context(&input, &mut context)
+        eval_in_context(&init_str, &mut context).unwrap();
+        eval_in_context(input, &mut context).unwrap()
     }
 
     #[test]
@@ -194,7 +746,7 @@ mod tests {
         assert_eq!(eval(&String::from("(def a 10) (def sq (fn sq (a) (* a a))) (sq a)")), Type::Number(100));
         assert_eq!(eval(&String::from("((fn sq (a) (* a a)) 10)")), Type::Number(100));
         assert_eq!(eval(&String::from("1")), Type::Number(1));
-        assert_eq!(eval(&String::from("")), Type::List(vec![]));
+        assert_eq!(eval(""), Type::Nil);
         assert_eq!(eval(&String::from("(+ 1 2)")), Type::Number(3));
         assert_eq!(eval(&String::from("(* 2 2)")), Type::Number(4));
         assert_eq!(eval(&String::from("(> 4 2)")), Type::Bool(true));
@@ -228,6 +780,13 @@ mod tests {
         assert_eq!(eval(&String::from("(not (or (eq 1 2) (> 2 3)))")), Type::Bool(true));
     }
 
+    #[test]
+    fn test_if() {
+        assert_eq!(eval("(if true 1)"), Type::Number(1));
+        assert_eq!(eval("(if false 1)"), Type::Nil);
+        assert_eq!(eval("(if false 1 2)"), Type::Number(2));
+    }
+
     #[test]
     fn test_named_lambdas() {
         assert_eq!(eval(&String::from("((fn sum (l) (if (> l 0) (+ l (sum (- l 1))) l)) 3)")),
@@ -266,12 +825,24 @@ mod tests {
                    Type::Number(100));
     }
 
+    #[test]
+    fn test_builtins_are_first_class() {
+        assert_eq!(eval("(apply list '(a (b) nil))").to_string(), "(a (b) nil)");
+        assert_eq!(eval("(apply cons (list car '(cdr)))").to_string(), "(#<native car> cdr)");
+        assert_eq!(eval("(apply (fn f (a b...) (list b a)) '(x y z))").to_string(), "((y z) x)");
+        assert_eq!(bootstrap_and_eval("(list (reduce '(1 2 3) +) (reduce '(1 2 3) 10 *))").to_string(), "(6 60)");
+        assert_eq!(bootstrap_and_eval("(map '((a b) (c)) car)").to_string(), "(a c)");
+        assert_eq!(bootstrap_and_eval("(map (list car cdr) (fn _ (f) (f '(1 2))))").to_string(), "(1 (2))");
+        assert_eq!(eval("(eq car (car (list car)))"), Type::Bool(true));
+        let error = eval_in_context("(apply if '(true 1 2))", &mut built_in::init_context()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime(String::from("Special form can't be applied -> if")));
+        assert_eq!(eval("(list if)").to_string(), "(#<special if>)");
+    }
+
     #[test]
     fn test_fn_eq() {
-        // TODO fix
-        // doesn't work :(
-        // assert_eq!(eval(&String::from("(eq + +)")),
-        //            Type::Bool(true));
+        assert_eq!(eval(&String::from("(eq + +)")),
+                   Type::Bool(true));
         assert_eq!(eval(&String::from("(eq + -)")),
                    Type::Bool(false));
         assert_eq!(eval(&String::from("(def a (fn a () 1))
@@ -334,6 +905,44 @@ mod tests {
                    Type::List(vec![]));
     }
 
+    #[test]
+    fn test_quote_shorthand() {
+        assert_eq!(eval(&String::from("'(1 a)")),
+                   Type::List(vec![Type::Number(1), Type::Symbol(String::from("a"))]));
+        assert_eq!(eval(&String::from("''a")),
+                   Type::List(vec![Type::Symbol(String::from("quote")), Type::Symbol(String::from("a"))]));
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        assert_eq!(eval(&String::from("(def b 2) `(a ,b ,@(list 3 4) ,@nil 5)")),
+                   Type::List(vec![Type::Symbol(String::from("a")), Type::Number(2), Type::Number(3),
+                                   Type::Number(4), Type::Number(5)]));
+        assert_eq!(eval(&String::from("(def b 2) (quasiquote (a (unquote b)))")),
+                   Type::List(vec![Type::Symbol(String::from("a")), Type::Number(2)]));
+        assert_eq!(eval(&String::from("`1")),
+                   Type::Number(1));
+        assert_eq!(eval_in_context("`,@(list 1)", &mut built_in::init_context()).unwrap_err().kind,
+                   ErrorKind::Runtime(String::from("unquote-splicing outside of a list")));
+        assert_eq!(eval("``,@(list 1)").to_string(), "(quasiquote (unquote-splicing (list 1)))");
+    }
+
+    #[test]
+    fn test_nested_quasiquote() {
+        let sym = |s: &str| Type::Symbol(String::from(s));
+        assert_eq!(eval(&String::from("`(1 `(2 ,(3 ,(+ 1 3))))")),
+                   Type::List(vec![Type::Number(1),
+                                   Type::List(vec![sym("quasiquote"),
+                                                   Type::List(vec![Type::Number(2),
+                                                                   Type::List(vec![sym("unquote"),
+                                                                                   Type::List(vec![Type::Number(3), Type::Number(4)])])])])]));
+        assert_eq!(eval(&String::from("(def x 1) `(a `(b ,@,x))")),
+                   Type::List(vec![sym("a"),
+                                   Type::List(vec![sym("quasiquote"),
+                                                   Type::List(vec![sym("b"),
+                                                                   Type::List(vec![sym("unquote-splicing"), Type::Number(1)])])])]));
+    }
+
     #[test]
     fn test_list() {
         assert_eq!(eval(&String::from("(list)")),
@@ -347,9 +956,11 @@ mod tests {
     #[test]
     fn test_car() {
         assert_eq!(eval(&String::from("(car (list))")),
-                   Type::List(vec![]));
+                   Type::Nil);
+        assert_eq!(eval(&String::from("(car nil)")),
+                   Type::Nil);
         assert_eq!(eval(&String::from("(car)")),
-                   Type::List(vec![]));
+                   Type::Nil);
         assert_eq!(eval(&String::from("(car (list (+ 0 0 0 0 1) 2 3))")),
                    Type::Number(1));
     }
@@ -360,10 +971,37 @@ mod tests {
                    Type::List(vec![Type::Number(2), Type::Number(3)]));
         assert_eq!(eval(&String::from("(cdr (list))")),
                    Type::List(vec![]));
+        assert_eq!(eval(&String::from("(cdr nil)")),
+                   Type::List(vec![]));
         assert_eq!(eval(&String::from("(cdr)")),
                    Type::List(vec![]));
     }
 
+    #[test]
+    fn test_get() {
+        assert_eq!(eval(&String::from("(get (list 1 2 3) 1)")),
+                   Type::Number(2));
+        assert_eq!(eval(&String::from("(get (list 1 2 3) 3)")),
+                   Type::Nil);
+        assert_eq!(eval(&String::from("(get nil 0)")),
+                   Type::Nil);
+    }
+
+    #[test]
+    fn test_nil() {
+        assert_eq!(eval(&String::from("nil")),
+                   Type::Nil);
+        assert_eq!(eval(&String::from("(list (nil? nil) (nil? (list)) (nil? (car (list))))")),
+                   Type::List(vec![Type::Bool(true), Type::Bool(false), Type::Bool(true)]));
+        assert_eq!(eval(&String::from("(eq nil (list))")),
+                   Type::Bool(false));
+        assert_eq!(eval(&String::from("(list nil (list))")),
+                   Type::List(vec![Type::Nil, Type::List(vec![])]));
+        assert_eq!(eval(&String::from("(cons 1 nil)")),
+                   Type::List(vec![Type::Number(1)]));
+        assert_eq!(format!("{:?}", eval(&String::from("(list 1 nil)"))), "[1, nil]");
+    }
+
     #[test]
     fn test_cons() {
         assert_eq!(eval(&String::from("(cons 0 (list 1 (+ 0 2) (+ 1 2)))")),
@@ -380,6 +1018,259 @@ mod tests {
                    Type::List(vec![Type::Number(1), Type::Number(2), Type::Number(3), Type::Number(1),]));
     }
 
+    #[test]
+    fn test_strings() {
+        assert_eq!(eval(&String::from("(list \"a b\" (quote \"c\"))")),
+                   Type::List(vec![Type::Str(String::from("a b")), Type::Str(String::from("c"))]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut context = built_in::init_context();
+        let mut error = |input: &str| eval_in_context(input, &mut context).unwrap_err().kind;
+        assert_eq!(error("(car 1)"),
+                   ErrorKind::Runtime(String::from("List expected -> 1")));
+        assert_eq!(error("x"),
+                   ErrorKind::Runtime(String::from("Symbol not found -> \"x\"")));
+        assert_eq!(error("(cons 1)"),
+                   ErrorKind::Runtime(String::from("Wrong number of arguments to cons -> expected 2, got 1")));
+        assert_eq!(error("(- )"),
+                   ErrorKind::Runtime(String::from("Wrong number of arguments to - -> expected 1 or more, got 0")));
+        assert_eq!(error("(* 0x7fffffffffffffff 2)"),
+                   ErrorKind::Runtime(String::from("Arithmetic overflow")));
+        assert_eq!(error("((fn f (a) a))"),
+                   ErrorKind::Runtime(String::from("Wrong number of arguments to f -> expected 1, got 0")));
+        assert_eq!(error("(throw (list 1))"),
+                   ErrorKind::Thrown(Type::List(vec![Type::Number(1)])));
+    }
+
+    #[test]
+    fn test_try_catch() {
+        assert_eq!(eval(&String::from("(try (+ 1 2) (catch e 0))")),
+                   Type::Number(3));
+        assert_eq!(eval(&String::from("(try (throw 42) (catch e (+ e 1)))")),
+                   Type::Number(43));
+        assert_eq!(eval(&String::from("(try (throw (ex-info \"bad\" 42)) (catch e (list (ex-message e) (ex-data e))))")),
+                   Type::List(vec![Type::Str(String::from("bad")), Type::Number(42)]));
+        assert_eq!(eval(&String::from("(try (car 1) (catch e (ex-message e)))")),
+                   Type::Str(String::from("List expected -> 1")));
+        assert_eq!(eval(&String::from("(try (try (throw 1) (catch e (throw (+ e 1)))) (catch e e))")),
+                   Type::Number(2));
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let mut context = built_in::init_context();
+        let error = eval_in_context("(def inner (fn inner (a) (car a)))
+                                     (def outer (fn outer (a) (let () (inner a))))
+                                     (outer 1)", &mut context).unwrap_err();
+        assert_eq!(error.stack.iter().map(|frame| frame.name.as_str()).collect::<Vec<_>>(), vec!["car", "inner", "outer"]);
+        assert_eq!(error.to_string(), "List expected -> 1 (<input>:1:26)\n    at car (<input>:1:26)\n    at inner (<input>:2:71)\n    \
+                                       at outer (<input>:3:38)");
+        assert_eq!(eval_in_context("(try (outer 1) (catch e (ex-stack e)))", &mut context),
+                   Ok(Type::List(["car (<input>:1:26)", "inner (<input>:2:71)", "outer (<input>:1:6)"]
+                       .iter().map(|f| Type::Str(f.to_string())).collect())));
+        assert_eq!(eval_in_context("(try ((fn thrower () (throw (ex-info \"bad\")))) (catch e (ex-stack e)))", &mut context),
+                   Ok(Type::List(["throw (<input>:1:22)", "thrower (<input>:1:6)"].iter().map(|f| Type::Str(f.to_string())).collect())));
+        assert_eq!(eval_in_context("(try (throw 1) (catch e (ex-stack e)))", &mut context),
+                   Ok(Type::Nil));
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let mut context = built_in::init_context();
+        eval_source(&String::from_utf8_lossy(include_bytes!("../res/init.lisp")), "init.lisp", &mut context).unwrap();
+        let error = eval_in_context("(def f (fn f (x)\n  (when true\n    (+ 1 y))))\n(f 1)", &mut context).unwrap_err();
+        let location = error.location.unwrap();
+        assert_eq!((location.line, location.col), (3, 5));
+        assert_eq!(error.stack.last().unwrap().to_string(), "f (<input>:4:1)");
+        let error = eval_source("\n  (car\n 1)", "file.lisp", &mut context).unwrap_err();
+        assert_eq!(error.to_string(), "List expected -> 1 (file.lisp:2:3)\n    at car (file.lisp:2:3)");
+        let error = macroexpand_all(&Type::Symbol(String::from("y")), &mut context).unwrap().eval(&mut context).unwrap_err();
+        assert_eq!(error.location, None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut context = built_in::init_context();
+        let error = |input: &str| {
+            let error = eval_in_context(input, &mut built_in::init_context()).unwrap_err();
+            let location = error.location.unwrap();
+            (error.kind, location.line, location.col)
+        };
+        let runtime = |message: &str| ErrorKind::Runtime(String::from(message));
+        assert_eq!(error("(+ 1 2))"), (runtime("Unexpected )"), 1, 8));
+        assert_eq!(error("(+ 1\n (car '(1 2)"), (runtime("Unclosed ("), 2, 2));
+        assert_eq!(error("(list 1 ')"), (runtime("Missing form after quote"), 1, 9));
+        assert_eq!(error("`"), (runtime("Missing form after quasiquote"), 1, 1));
+        assert_eq!(error("(str \"abc"), (runtime("Unterminated string"), 1, 6));
+        assert_eq!(eval_in_context("", &mut context), Ok(Type::Nil));
+        assert_eq!(eval_in_context(" \n\t ", &mut context), Ok(Type::Nil));
+        let deep = format!("{}1{}", "(".repeat(200_000), ")".repeat(200_000));
+        assert_eq!(parser::read(&deep), Err(ParseError::TooDeep(Position { line: 1, col: parser::MAX_DEPTH + 1 })));
+        let incomplete = |input: &str| parser::read(input).unwrap_err().is_incomplete();
+        assert!(incomplete("(a\n(b)") && incomplete("\"a") && incomplete("#| a"));
+        assert!(!incomplete("a)") && !incomplete("(a ')") && !incomplete("(a #_)"));
+        assert!(["'", "`", "(a ,", ",@", "a #_"].iter().all(|input| incomplete(input)));
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        // whole read and eval path, quoted data as well as nested calls, on a stack as large as the main thread's
+        let nested = |open: &str, depth: usize| format!("{}1{}", open.repeat(depth), ")".repeat(depth));
+        let deepest = parser::MAX_DEPTH - 2;
+        std::thread::Builder::new().stack_size(8 << 20).spawn(move || {
+            assert_eq!(eval(&format!("(car (quote {}))", nested("(", deepest))).to_string(), nested("(", deepest - 1));
+            assert_eq!(eval(&format!("(car {})", nested("(list ", deepest))).to_string(), nested("(", deepest - 1));
+            assert_eq!(eval(&format!("(is-list (read-string \"{}\"))", nested("(", parser::MAX_DEPTH))), Type::Bool(true));
+            assert_eq!(eval(&format!("(try (read-string \"{}\") (catch e (ex-message e)))", nested("(", 200_000))),
+                       Type::Str(format!("Nesting deeper than {}", parser::MAX_DEPTH)));
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(eval("; sum\n(+ 1 #_2 #_ #_4 5 3) ; four"), Type::Number(4));
+        assert_eq!(eval("#| (car 1) #| |# |# '#_a b"), Type::Symbol(String::from("b")));
+        assert_eq!(eval_in_context("(list #_)", &mut built_in::init_context()).unwrap_err().kind,
+                   ErrorKind::Runtime(String::from("Missing form after #_")));
+    }
+
+    #[test]
+    fn test_load_and_require() {
+        let dir = std::env::temp_dir().join(format!("lesp-test-require-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, source: &str| std::fs::write(dir.join(name), source).unwrap();
+        write("main.lisp", "(require 'lib/counter) (require \"lib/counter\") (load \"lib/value.lisp\")");
+        write("lib/counter.lisp", "(def count (+ count 1))");
+        write("lib/value.lisp", "(def value (+ count 41))");
+        write("lib/a.lisp", "(require 'b)");
+        write("lib/b.lisp", "(require 'a)");
+
+        let mut context = built_in::init_context();
+        context.insert(String::from("count"), Type::Number(0));
+        built_in::load_file(&mut context, &dir.join("main.lisp")).unwrap();
+        assert_eq!(context.get("count"), Some(&Type::Number(1)));
+        assert_eq!(context.get("value"), Some(&Type::Number(42)));
+        assert_eq!(context.get(built_in::FILE), None);
+        // library state can't be reached from code
+        eval_in_context("(def *loaded-libs* '(())) (def *loading-libs* 5)", &mut context).unwrap();
+        built_in::load_file(&mut context, &dir.join("main.lisp")).unwrap();
+        assert_eq!(context.get("count"), Some(&Type::Number(1)));
+
+        let error = built_in::load_file(&mut context, &dir.join("lib/a.lisp")).unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("Circular require -> "), "{}", message);
+        assert!(message.contains("a.lisp") && message.contains("b.lisp"), "{}", message);
+        assert_eq!(eval_in_context("(require 'missing)", &mut context).unwrap_err().kind,
+                   ErrorKind::Runtime(String::from("Library not found -> missing")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let dir = std::env::temp_dir().join(format!("lesp-test-ns-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("geometry.lisp"), "(ns geometry)
+                                                   (defmacro unless (test body...) `(if ,test nil (do ,@body)))
+                                                   (defn- twice (x) (* x 2))
+                                                   (defn perimeter (a b) (twice (+ a b)))
+                                                   (def car 4)").unwrap();
+        let mut context = built_in::init_context_with_prelude();
+        context.insert(String::from(built_in::FILE), Type::Str(dir.join("main.lisp").to_string_lossy().to_string()));
+        let mut eval = |input: &str| eval_in_context(input, &mut context);
+        let runtime = |message: &str| Err(ErrorKind::Runtime(String::from(message)));
+
+        assert_eq!(eval("(require 'geometry :as g :refer (perimeter))"), Ok(Type::Symbol(String::from("geometry"))));
+        assert_eq!(eval("(list (geometry/perimeter 1 2) (g/perimeter 2 3) (perimeter 3 4) g/car)"),
+                   Ok(Type::List(vec![Type::Number(6), Type::Number(10), Type::Number(14), Type::Number(4)])));
+        assert_eq!(eval("(car (list 1))"), Ok(Type::Number(1)));
+        assert_eq!(eval("twice").map_err(|e| e.kind), runtime("Symbol not found -> \"twice\""));
+        assert_eq!(eval("(g/twice 1)").map_err(|e| e.kind), runtime("Private definition -> geometry/twice"));
+        assert_eq!(eval("(geometry/twice 1)").map_err(|e| e.kind), runtime("Private definition -> geometry/twice"));
+        assert_eq!(eval("(require 'geometry :refer (twice))").map_err(|e| e.kind), runtime("Private definition -> geometry/twice"));
+
+        assert_eq!(eval("(in-ns 'geometry) (list (twice 1) car)"), Ok(Type::List(vec![Type::Number(2), Type::Number(4)])));
+        assert_eq!(eval("(ns user) (list (car (list 5)) reduce_)").map_err(|e| e.kind), runtime("Symbol not found -> \"reduce_\""));
+        assert_eq!(eval("(require 'geometry :refer :all) (perimeter 1 1)"), Ok(Type::Number(4)));
+        assert_eq!(eval("(ns scratch) (def x 1) (in-ns 'user) (list scratch/x (nil? (try x (catch e nil))))"),
+                   Ok(Type::List(vec![Type::Number(1), Type::Bool(true)])));
+        // aliased macros are expanded ahead of evaluation too
+        let read = |s: &str| parser::read(s).unwrap().0.remove(0);
+        assert_eq!(eval("(macroexpand-1 '(g/unless a b))"), Ok(read("(if a nil (do b))")));
+        assert_eq!(eval("(macroexpand-all '(fn _ (x) (geometry/unless x 1)))"), Ok(read("(fn _ (x) (if x nil (let () 1)))")));
+        assert_eq!(eval("(macroexpand-all '(fn _ (g/unless) (g/unless 1)))"), Ok(read("(fn _ (g/unless) (g/unless 1))")));
+        // options referring a macro are left as they are on a second require
+        for _ in 0..2 {
+            assert_eq!(eval("(require 'geometry :refer (unless perimeter)) (unless false (perimeter 1 2))"), Ok(Type::Number(6)));
+        }
+        // namespace state can't be reached from code
+        assert_eq!(eval("(def *private* 1) (def *aliases* '((user))) (g/twice 1)").map_err(|e| e.kind),
+                   runtime("Private definition -> geometry/twice"));
+        assert_eq!(eval("x/y").map_err(|e| e.kind), runtime("Symbol not found -> \"x/y\""));
+        assert_eq!(eval("*ns*").map_err(|e| e.kind), runtime("Symbol not found -> \"*ns*\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_print() {
+        assert_eq!(eval("'(a \"b\\\"c\" (1 -2) () nil true)").to_string(), "(a \"b\\\"c\" (1 -2) () nil true)");
+        assert_eq!(eval("(list car (fn f () 1) (macro m () 1))").to_string(), "(#<native car> #<fn f> #<macro m>)");
+        assert_eq!(eval("(pr-str \"a\\nb\" '(quote x) 3)"), Type::Str(String::from("\"a\\nb\" (quote x) 3")));
+        assert_eq!(eval("(read-string \"(+ 1 2) ignored\")"), parser::read("(+ 1 2)").unwrap().0.remove(0));
+        assert_eq!(eval("(list (read-string \"\") (eq (read-string (pr-str '(a \"\\\\\"))) '(a \"\\\\\")))"),
+                   Type::List(vec![Type::Nil, Type::Bool(true)]));
+        assert_eq!(eval_in_context("(read-string \"(\")", &mut built_in::init_context()).unwrap_err().kind,
+                   ErrorKind::Runtime(String::from("Unclosed (")));
+    }
+
+    #[test]
+    fn test_print_read_round_trip() {
+        // small deterministic generator, so every run checks the same values
+        let mut seed: u64 = 42;
+        let mut next = move |bound: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        fn value(next: &mut dyn FnMut(u64) -> u64, depth: usize) -> Type {
+            let symbols = ["a", "+", "->>", "x#", "nil?", "foo/bar", "rest...", "λ", "1+", "-"];
+            let chars = ['a', ' ', '"', '\\', '\n', '\t', ';', '(', ')', '\'', 'é', '#'];
+            let numbers = [0, 1, -1, 42, i64::MAX, i64::MIN];
+            match next(if depth > 3 { 6 } else { 8 }) {
+                0 => Type::Nil,
+                1 => Type::Bool(next(2) == 0),
+                2 => Type::Number(numbers[next(numbers.len() as u64) as usize]),
+                3 => Type::Number(next(1_000_000) as i64 - 500_000),
+                4 => Type::Str((0..next(6)).map(|_| chars[next(chars.len() as u64) as usize]).collect()),
+                5 => Type::Symbol(String::from(symbols[next(symbols.len() as u64) as usize])),
+                _ => Type::List((0..next(5)).map(|_| value(next, depth + 1)).collect())
+            }
+        }
+        for _ in 0..500 {
+            let original = value(&mut next, 0);
+            let printed = original.to_string();
+            let (forms, _) = parser::read(&printed).unwrap();
+            assert_eq!(forms, vec![original], "{}", printed);
+        }
+    }
+
+    #[test]
+    fn test_finally() {
+        assert_eq!(eval(&String::from("(try 1 (finally (def x 2)))")),
+                   Type::Number(1));
+        assert_eq!(eval(&String::from("(try (try (car 1) (finally (def x 2))) (catch e x))")),
+                   Type::Number(2));
+        assert_eq!(eval(&String::from("(try (try (throw 1) (catch e (throw e)) (finally (def x 3))) (catch e (+ e x)))")),
+                   Type::Number(4));
+        // definitions made in catch are kept, its binding is not
+        assert_eq!(eval("(try (throw 1) (catch e (def x (+ e 1)))) (list x (try e (catch _ nil)))").to_string(), "(2 nil)");
+        // a malformed catch fails before the body runs
+        let mut context = built_in::init_context();
+        assert_eq!(eval_in_context("(def n 0) (try (def n 1) (catch (x) 3) (finally (def n 2)))", &mut context).unwrap_err().kind,
+                   ErrorKind::Runtime(String::from("Symbol expected -> (x)")));
+        assert_eq!(context.get("n"), Some(&Type::Number(0)));
+    }
+
     #[test]
     fn integration_1() {
         assert_eq!(bootstrap_and_eval(&String::from("(-> 10 (genlist) (map square) (map square))")),
@@ -388,6 +1279,97 @@ mod tests {
                                    Type::Number(16), Type::Number(1)]));
     }
 
+    #[test]
+    fn integration_cond_when() {
+        assert_eq!(bootstrap_and_eval(&String::from("(list (cond (> 1 2) 1 (> 3 2) 2) (cond (> 1 2) 1) (when (> 2 1) 1 2))")),
+                   Type::List(vec![Type::Number(2), Type::Nil, Type::Number(2)]));
+    }
+
+    #[test]
+    fn test_gensym() {
+        let result = eval(&String::from("(list (gensym) (gensym) (gensym 'tmp))"));
+        if let Type::List(syms) = result {
+            assert_ne!(syms[0], syms[1]);
+            assert!(matches!(&syms[2], Type::Symbol(s) if s.starts_with("tmp__")));
+        } else { panic!() }
+    }
+
+    #[test]
+    fn test_auto_gensym() {
+        let result = eval(&String::from("(def f (fn f () `(a# a# b#))) (list (f) (f))"));
+        if let Type::List(expansions) = result {
+            assert_eq!(expansions.len(), 2);
+            if let (Type::List(first), Type::List(second)) = (&expansions[0], &expansions[1]) {
+                assert_eq!(first[0], first[1]);
+                assert_ne!(first[0], first[2]);
+                assert_ne!(first[0], second[0]);
+            } else { panic!() }
+        } else { panic!() }
+    }
+
+    #[test]
+    fn integration_hygienic_macro() {
+        assert_eq!(bootstrap_and_eval(&String::from("(defmacro with-ten (body) `(let ((ten 10)) (+ ten ,body)))
+                                                     (let ((ten 1)) (with-ten ten))")),
+                   Type::Number(20));
+        assert_eq!(bootstrap_and_eval(&String::from("(defhmacro with-ten (body) `(let ((ten 10)) (+ ten ,body)))
+                                                     (let ((ten 1)) (with-ten ten))")),
+                   Type::Number(11));
+        assert_eq!(bootstrap_and_eval(&String::from("(defhmacro sum-all (xs...) `((fn _ (rest...) (reduce rest 0 +)) ,@xs))
+                                                     (let ((rest 1)) (sum-all rest 2 3))")),
+                   Type::Number(6));
+        // only references within the binding's scope are renamed
+        assert_eq!(bootstrap_and_eval("(defhmacro m (xs) `(let ((first (first ,xs))) (+ first 1))) (m '(1 2))"),
+                   Type::Number(2));
+        assert_eq!(bootstrap_and_eval("(defhmacro m2 (v) `(list (let ((list 1)) list) ,v)) (m2 2)").to_string(), "(1 2)");
+        assert_eq!(bootstrap_and_eval("(defhmacro m3 (v) `(let ((a ,v) (b (+ a 1))) (list a b))) (let ((a 5)) (m3 a))").to_string(),
+                   "(5 6)");
+    }
+
+    #[test]
+    fn integration_locals_shadow_macros() {
+        assert_eq!(bootstrap_and_eval("(defn f (cond) (cond 1)) (f (fn _ (x) (+ x 1)))"), Type::Number(2));
+        assert_eq!(bootstrap_and_eval("(let ((do (fn _ (x) x))) (do 7))"), Type::Number(7));
+        assert_eq!(bootstrap_and_eval("(defn g (do) do) (g 3)"), Type::Number(3));
+        assert_eq!(bootstrap_and_eval("(try (throw (fn _ (a) a)) (catch when (when 4)))"), Type::Number(4));
+        // the macro is visible again outside of the binding
+        assert_eq!(bootstrap_and_eval("(list ((fn _ (when) (when 1)) square) (when true 2))").to_string(), "(1 2)");
+    }
+
+    #[test]
+    fn integration_macroexpand() {
+        let read = |s: &str| parser::read(s).unwrap().0.remove(0);
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand-1 '(when a b))")),
+                   read("(if a (do b) nil)"));
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand-1 '(+ 1 2))")),
+                   read("(+ 1 2)"));
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand '(-> 1 (+ 2) (* 3)))")),
+                   read("(* (+ 1 2) 3)"));
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand '(defn f (a) a))")),
+                   read("(def f (fn f (a) a))"));
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand-all '(defn f (a) (when a '(when 1))))")),
+                   read("(def f (fn f (a) (if a (let () (quote (when 1))) nil)))"));
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand-all '(let ((x 1)) `(when ,(when a b))))")),
+                   read("(let ((x 1)) (quasiquote (when (unquote (if a (let () b) nil)))))"));
+        // locals shadow macros
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand-all '(fn f (when x...) (list (when x) (comment x))))")),
+                   read("(fn f (when x...) (list (when x) nil))"));
+        assert_eq!(bootstrap_and_eval(&String::from("(macroexpand-all '(let ((a (when 1 2)) (when list)) (when a)))")),
+                   read("(let ((a (if 1 (let () 2) nil)) (when list)) (when a))"));
+    }
+
+    #[test]
+    fn integration_fn_body_is_expanded() {
+        let mut context = built_in::init_context();
+        eval_in_context(&String::from_utf8_lossy(include_bytes!("../res/init.lisp")), &mut context).unwrap();
+        eval_in_context("(defn f (a) (when (> a 1) (-> a (+ 1))))", &mut context).unwrap();
+        if let Some(Type::Function(FunctionType::UserDefined(f))) = context.get("f") {
+            let body = parser::read("(if (> a 1) (let () (+ a 1)) nil)").unwrap().0;
+            assert_eq!(f.body, body);
+        } else { panic!() }
+        assert_eq!(eval_in_context("(f 2)", &mut context), Ok(Type::Number(3)));
+    }
+
     #[test]
     fn integration_2() {
         assert_eq!(bootstrap_and_eval(&String::from("(-> (list 1 2 3) (reverse))")),
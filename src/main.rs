@@ -1,20 +1,11 @@
-mod lexer;
-mod lisp;
-mod parser;
-mod built_in;
 #[cfg(feature = "web-spa")]
 mod browser;
-
-use std::io::{self, BufRead, Write};
+#[cfg(not(feature = "web-spa"))]
+mod repl;
 
 #[cfg(feature = "web-spa")]
 fn main() {
-    let mut context = built_in::init_context();
-
-    let bytes = include_bytes!("../res/init.lisp");
-    let init_str = String::from_utf8_lossy(bytes).to_string();
-
-    lisp::eval_in_context(&init_str, &mut context);
+    let lisp = lesp::Interpreter::with_prelude();
 
     use stdweb::web::*;
     use yew::prelude::*;
@@ -23,32 +14,191 @@ fn main() {
 
     yew::initialize();
 
-    let app: App<_, browser::RootModel> = App::new(context);
+    let app: App<_, browser::RootModel> = App::new(lisp);
     app.mount_to_body();
     yew::run_loop();
 }
 
+#[cfg(not(feature = "web-spa"))]
+const USAGE: &str = "\
+Usage: lesp [--no-prelude] [-e expr]... [file [args...]]
+       lesp fmt [--check] [file...]
+  file           run a script, remaining arguments are bound to *argv*
+  -e expr        evaluate expr and print the result, arguments are bound to *argv*
+  --no-prelude   don't load the standard library
+  fmt            reformat files in place, or stdin to stdout when no file is given
+  --check        only list files that aren't formatted
+Starts the REPL when neither file nor -e is given";
+
 #[cfg(not(feature = "web-spa"))]
 fn main() {
-    let mut context = built_in::init_context();
+    std::process::exit(run(std::env::args().skip(1).collect()));
+}
+
+// returns the exit code, 1 for evaluation errors and 2 for bad usage
+#[cfg(not(feature = "web-spa"))]
+fn run(args: Vec<String>) -> i32 {
+    if args.first().map(String::as_str) == Some("fmt") {
+        return fmt(&args[1..]);
+    }
+    let mut prelude = true;
+    let mut exprs = vec![];
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-prelude" => prelude = false,
+            "-e" => match args.next() {
+                Some(expr) => exprs.push(expr),
+                None => {
+                    eprintln!("-e expects an expression\n{}", USAGE);
+                    return 2;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0;
+            },
+            "--" => {
+                positional.extend(args.by_ref());
+            },
+            option if option.starts_with('-') && option.len() > 1 => {
+                eprintln!("Unknown option {}\n{}", option, USAGE);
+                return 2;
+            },
+            _ => {
+                positional.push(arg);
+                positional.extend(args.by_ref());
+            }
+        }
+    }
+
+    let script = if exprs.is_empty() && !positional.is_empty() {
+        Some(positional.remove(0))
+    } else {
+        None
+    };
+    let argv = lesp::Value::List(positional.into_iter().map(lesp::Value::Str).collect());
+    // the REPL starts over from the same state on :reset
+    let init = || {
+        let mut lisp = if prelude {
+            lesp::Interpreter::with_prelude()
+        } else {
+            lesp::Interpreter::new()
+        };
+        lisp.set("*argv*", argv.clone());
+        lisp
+    };
+    let mut lisp = init();
 
-    let bytes = include_bytes!("../res/init.lisp");
-    let init_str = String::from_utf8_lossy(bytes).to_string();
-    lisp::eval_in_context(&init_str, &mut context);
+    if let Some(path) = script {
+        if let Err(e) = lisp.eval_file(&path) {
+            eprintln!("{}", e);
+            return 1;
+        }
+    } else if !exprs.is_empty() {
+        for expr in exprs {
+            match lisp.eval_source(&expr, "-e") {
+                Ok(result) => println!("{}", result),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            }
+        }
+    } else {
+        repl::run(&mut lisp, &init);
+    }
+    0
+}
+
+#[cfg(not(feature = "web-spa"))]
+fn fmt(args: &[String]) -> i32 {
+    use std::io::Read;
 
-    let stdin = io::stdin();
-    print(">> ");
-    
-    for line in stdin.lock().lines() {
-        let r = lisp::eval_in_context(&String::from(line.unwrap()), &mut context);
-        println!("<< {:?}", r);
-        print(">> ");
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if files.is_empty() {
+        let mut input = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut input) {
+            eprintln!("Cannot read stdin -> {}", e);
+            return 1;
+        }
+        return match lesp::format_source(&input, "<stdin>", lesp::WIDTH) {
+            Ok(formatted) if check => (formatted != input) as i32,
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        };
+    }
+    let mut code = 0;
+    for file in files {
+        let input = match std::fs::read_to_string(file) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("Cannot read {} -> {}", file, e);
+                code = 1;
+                continue;
+            }
+        };
+        match lesp::format_source(&input, file, lesp::WIDTH) {
+            Ok(formatted) if formatted == input => (),
+            Ok(_) if check => {
+                println!("{}", file);
+                code = 1;
+            },
+            Ok(formatted) => if let Err(e) = std::fs::write(file, formatted) {
+                eprintln!("Cannot write {} -> {}", file, e);
+                code = 1;
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                code = 1;
+            }
+        }
     }
+    code
 }
 
-fn print(s: &str) {
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
-    handle.write(&s.as_bytes()).expect("Cannot write to stdout");
-    handle.flush().expect("Cannot write to stdout");
-}
\ No newline at end of file
+#[cfg(all(test, not(feature = "web-spa")))]
+mod tests {
+    use super::*;
+
+    fn run_args(args: &[&str]) -> i32 {
+        run(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run_args(&["-e", "(+ 1 2)", "-e", "(defn f () 1)"]), 0);
+        assert_eq!(run_args(&["-e", "(car 1)"]), 1);
+        assert_eq!(run_args(&["--no-prelude", "-e", "(defn f () 1)"]), 1);
+        assert_eq!(run_args(&["--no-prelude", "-e", "(+ 1 2)"]), 0);
+        assert_eq!(run_args(&["--help"]), 0);
+        // bad usage
+        assert_eq!(run_args(&["-e"]), 2);
+        assert_eq!(run_args(&["-x", "-e", "1"]), 2);
+        assert_eq!(run_args(&["-e", "1", "-x"]), 2);
+        // arguments after -- are never options
+        assert_eq!(run_args(&["-e", "(if (eq *argv* '(\"-x\" \"a\")) 1 (throw 1))", "--", "-x", "a"]), 0);
+        assert_eq!(run_args(&["-e", "(if (eq *argv* '()) 1 (throw 1))"]), 0);
+    }
+
+    #[test]
+    fn test_run_script() {
+        let path = std::env::temp_dir().join(format!("lesp-test-main-{}.lisp", std::process::id()));
+        std::fs::write(&path, "(if (eq *argv* '(\"a\" \"-x\")) 1 (throw 1))").unwrap();
+        let path = path.to_string_lossy().to_string();
+        // everything after the script belongs to it
+        assert_eq!(run_args(&[&path, "a", "-x"]), 0);
+        assert_eq!(run_args(&["--", &path, "a", "-x"]), 0);
+        assert_eq!(run_args(&[&path, "a"]), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(run_args(&[&path]), 1);
+    }
+}
@@ -0,0 +1,172 @@
+// Definitions made in namespace `foo` are bound as `foo/name` and, while `foo` is current,
+// as plain `name` too. Switching namespaces hides the plain names of the one being left and
+// brings back what they shadowed. Definitions of the root `user` namespace and builtins stay
+// visible everywhere.
+
+use crate::lisp::{Context, EvalError, Type};
+
+pub const USER: &str = "user";
+
+// state of namespaces, the leading space keeps it out of reach of symbols and apart from
+// shadowed values, which always start with a namespace name
+const CURRENT: &str = " ns";
+const PRIVATE: &str = " private";
+const ALIASES: &str = " aliases";
+
+/// Name of the namespace definitions currently go to
+pub fn current(context: &Context) -> String {
+    match context.get(CURRENT) {
+        Some(Type::Symbol(ns)) => ns.clone(),
+        _ => String::from(USER)
+    }
+}
+
+/// `foo/name` -> `Some(("foo", "name"))`
+pub fn split(name: &str) -> Option<(&str, &str)> {
+    match name.find('/') {
+        Some(i) if i > 0 && i + 1 < name.len() => Some((&name[..i], &name[i + 1..])),
+        _ => None
+    }
+}
+
+fn qualify(ns: &str, name: &str) -> String {
+    format!("{}/{}", ns, name)
+}
+
+// value a namespace's definition shadowed while the namespace is current,
+// the space keeps it out of reach of symbols
+fn shadowed(ns: &str, name: &str) -> String {
+    format!("{} {}", ns, name)
+}
+
+fn list(context: &Context, key: &str) -> Vec<Type> {
+    match context.get(key) {
+        Some(Type::List(elems)) => elems.clone(),
+        _ => vec![]
+    }
+}
+
+// plain names of everything defined in `ns`
+fn names(context: &Context, ns: &str) -> Vec<String> {
+    let prefix = qualify(ns, "");
+    context.keys().filter_map(|key| key.strip_prefix(&prefix)).map(String::from).collect()
+}
+
+pub fn is_private(context: &Context, qualified: &str) -> bool {
+    list(context, PRIVATE).iter().any(|name| matches!(name, Type::Symbol(s) if s == qualified))
+}
+
+/// Public definitions of `ns`
+pub fn exports(context: &Context, ns: &str) -> Vec<String> {
+    let mut exports: Vec<String> = names(context, ns).into_iter()
+        .filter(|name| !is_private(context, &qualify(ns, name)))
+        .collect();
+    exports.sort();
+    exports
+}
+
+/// Binds `name` in the current namespace, private names can't be referred to from other namespaces
+pub fn define(context: &mut Context, name: &str, value: Type, private: bool) {
+    let ns = current(context);
+    let qualified = qualify(&ns, name);
+    if ns != USER {
+        if !context.contains_key(&qualified) {
+            if let Some(previous) = context.get(name).cloned() {
+                context.insert(shadowed(&ns, name), previous);
+            }
+        }
+        context.insert(qualified.clone(), value.clone());
+    }
+    if private && !is_private(context, &qualified) {
+        let mut private_names = list(context, PRIVATE);
+        private_names.push(Type::Symbol(qualified));
+        context.insert(String::from(PRIVATE), Type::List(private_names));
+    }
+    context.insert(String::from(name), value);
+}
+
+/// Makes `to` the current namespace
+pub fn switch(context: &mut Context, to: &str) {
+    let from = current(context);
+    if from == to {
+        return;
+    }
+    if from != USER {
+        for name in names(context, &from) {
+            match context.remove(&shadowed(&from, &name)) {
+                Some(previous) => context.insert(name, previous),
+                None => context.remove(&name)
+            };
+        }
+    }
+    if to != USER {
+        for name in names(context, to) {
+            let value = context[&qualify(to, &name)].clone();
+            if let Some(previous) = context.insert(name.clone(), value) {
+                context.insert(shadowed(to, &name), previous);
+            }
+        }
+    }
+    context.insert(String::from(CURRENT), Type::Symbol(String::from(to)));
+}
+
+/// Unbinds private definitions of `ns`, functions that captured them keep working
+pub fn hide_private(context: &mut Context, ns: &str) {
+    let prefix = qualify(ns, "");
+    let (hidden, kept): (Vec<Type>, Vec<Type>) = list(context, PRIVATE).into_iter()
+        .partition(|name| matches!(name, Type::Symbol(s) if s.starts_with(&prefix)));
+    for name in hidden {
+        if let Type::Symbol(qualified) = name {
+            context.remove(&qualified[prefix.len()..]);
+            context.remove(&qualified);
+        }
+    }
+    if kept.is_empty() {
+        context.remove(PRIVATE);
+    } else {
+        context.insert(String::from(PRIVATE), Type::List(kept));
+    }
+}
+
+/// `(require 'lib :as alias)`, aliases belong to the namespace they were made in
+pub fn add_alias(context: &mut Context, alias: &str, target: &str) {
+    let ns = Type::Symbol(current(context));
+    let alias = Type::Symbol(String::from(alias));
+    let mut aliases: Vec<Type> = list(context, ALIASES).into_iter()
+        .filter(|entry| !matches!(entry, Type::List(e) if matches!(e.as_slice(), [n, a, _] if *n == ns && *a == alias)))
+        .collect();
+    aliases.push(Type::List(vec![ns, alias, Type::Symbol(String::from(target))]));
+    context.insert(String::from(ALIASES), Type::List(aliases));
+}
+
+fn alias_target(context: &Context, alias: &str) -> Option<String> {
+    let ns = Type::Symbol(current(context));
+    let alias = Type::Symbol(String::from(alias));
+    list(context, ALIASES).into_iter().find_map(|entry| match entry {
+        Type::List(e) => match e.as_slice() {
+            [n, a, Type::Symbol(target)] if *n == ns && *a == alias => Some(target.clone()),
+            _ => None
+        },
+        _ => None
+    })
+}
+
+/// Errors when `name` is a private definition of another namespace
+pub fn check_access(context: &Context, name: &str) -> Result<(), EvalError> {
+    match split(name) {
+        Some((ns, _)) if ns != current(context) && is_private(context, name) => {
+            Err(EvalError::runtime(format!("Private definition -> {}", name)))
+        },
+        _ => Ok(())
+    }
+}
+
+/// Looks up a name that isn't bound as is, `alias/name` is resolved through aliases of the current namespace
+pub fn resolve(context: &Context, name: &str) -> Result<Type, EvalError> {
+    let not_found = || EvalError::runtime(format!("Symbol not found -> {:?}", name));
+    let (ns, local) = split(name).ok_or_else(not_found)?;
+    let target = alias_target(context, ns).ok_or_else(not_found)?;
+    let qualified = qualify(&target, local);
+    check_access(context, &qualified)?;
+    context.get(&qualified).cloned().ok_or_else(not_found)
+}
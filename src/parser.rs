@@ -1,33 +1,157 @@
-use crate::lexer::{Tokens};
+use std::fmt;
+
+use crate::lexer::{self, Tokens, Position};
 use crate::lisp::{Type};
 
-pub fn build(tokens:&Vec<Tokens>, i: usize) -> (Vec<Type>, usize) {
-    let mut elems:Vec<Type> = vec![];
-    let mut current_pointer = i;
-    loop {
-        let current_token_ = tokens.get(current_pointer);
-        match current_token_ {
-            Some(current_token) => {
-                match current_token {
-                    Tokens::Symbol(s) => {
-                        elems.push(Type::Symbol(s.clone()))
-                    },
-                    Tokens::Number(n) => {
-                        elems.push(Type::Number(n.clone()));
-                    },
-                    Tokens::OP => {
-                        let (node, p) = build(&tokens, current_pointer + 1);
-                        elems.push(node.get(0).unwrap().clone());
-                        current_pointer = p;
-                    },
-                    Tokens::CP => {
-                        let node = Type::List(elems);
-                        return (vec![node], current_pointer);
-                    }
-                }
+/// Where a form starts in the source, mirroring the form's list structure
+/// (`children` is empty for atoms).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMap {
+    pub position: Position,
+    pub children: Vec<SourceMap>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// `)` without matching `(`
+    UnexpectedClose(Position),
+    /// `(` that is never closed
+    Unclosed(Position),
+    /// `"` that is never closed
+    UnterminatedString(Position),
+    /// `#|` that is never closed
+    UnterminatedComment(Position),
+    /// number literal that doesn't fit into 64 bits
+    NumberOutOfRange(Position),
+    /// form nested deeper than `MAX_DEPTH`
+    TooDeep(Position),
+    /// `'`, `` ` ``, `,`, `,@` or `#_` not followed by a form, holds the name of the form it stands for
+    MissingForm(&'static str, Position),
+    /// same as `MissingForm`, but at the end of input where the form may still follow
+    MissingFormAtEnd(&'static str, Position)
+}
+
+impl ParseError {
+    /// True when more input could make the source readable, e.g. an unclosed `(` or string
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, ParseError::Unclosed(_) | ParseError::UnterminatedString(_) | ParseError::UnterminatedComment(_) |
+                       ParseError::MissingFormAtEnd(_, _))
+    }
+
+    pub fn position(&self) -> Position {
+        match self {
+            ParseError::UnexpectedClose(position) | ParseError::Unclosed(position) |
+            ParseError::UnterminatedString(position) | ParseError::UnterminatedComment(position) |
+            ParseError::NumberOutOfRange(position) | ParseError::TooDeep(position) |
+            ParseError::MissingForm(_, position) | ParseError::MissingFormAtEnd(_, position) => *position
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedClose(_) => f.write_str("Unexpected )"),
+            ParseError::Unclosed(_) => f.write_str("Unclosed ("),
+            ParseError::UnterminatedString(_) => f.write_str("Unterminated string"),
+            ParseError::UnterminatedComment(_) => f.write_str("Unterminated comment"),
+            ParseError::NumberOutOfRange(_) => f.write_str("Number out of range"),
+            ParseError::TooDeep(_) => write!(f, "Nesting deeper than {}", MAX_DEPTH),
+            ParseError::MissingForm(name, _) | ParseError::MissingFormAtEnd(name, _) => write!(f, "Missing form after {}", name)
+        }
+    }
+}
+
+/// How deeply forms may nest, reading is iterative but evaluating, expanding and
+/// dropping forms recurse, so deeper input could overflow the stack after reading
+pub const MAX_DEPTH: usize = 512;
+
+// partially built form, the parser keeps them on its own stack instead of recursing
+enum Open {
+    List(Vec<Type>, Vec<SourceMap>, Position),
+    /**
+    'a   -> (quote a)
+    `a   -> (quasiquote a)
+    ,a   -> (unquote a)
+    ,@a  -> (unquote-splicing a)
+    */
+    Prefixed(&'static str, Position),
+    /// `#_a` -> nothing
+    Discard(Position)
+}
+
+/// Reads all forms in `input` together with their source maps
+pub fn read(input: &str) -> Result<(Vec<Type>, Vec<SourceMap>), ParseError> {
+    let (tokens, positions) = lexer::parse_fsm(input)?;
+    build(&tokens, &positions)
+}
+
+// returns all forms together with their source maps
+pub fn build(tokens: &[Tokens], positions: &[Position]) -> Result<(Vec<Type>, Vec<SourceMap>), ParseError> {
+    let mut forms = vec![];
+    let mut maps = vec![];
+    let mut open: Vec<Open> = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let position = positions.get(i).copied().unwrap_or_default();
+        let opens = matches!(token, Tokens::OP | Tokens::Quote | Tokens::Quasiquote | Tokens::Unquote | Tokens::UnquoteSplicing);
+        if opens && open.len() >= MAX_DEPTH {
+            return Err(ParseError::TooDeep(position));
+        }
+        let leaf = SourceMap { position, children: vec![] };
+        let (mut node, mut map) = match token {
+            Tokens::Symbol("nil") => (Type::Nil, leaf),
+            Tokens::Symbol("true") => (Type::Bool(true), leaf),
+            Tokens::Symbol("false") => (Type::Bool(false), leaf),
+            Tokens::Symbol(s) => (Type::Symbol(String::from(*s)), leaf),
+            Tokens::Number(n) => (Type::Number(*n), leaf),
+            Tokens::Str(s) => (Type::Str(s.to_string()), leaf),
+            Tokens::OP => {
+                open.push(Open::List(vec![], vec![], position));
+                continue;
             },
-            None => return (elems, current_pointer)
+            Tokens::Quote => {open.push(Open::Prefixed("quote", position)); continue;},
+            Tokens::Quasiquote => {open.push(Open::Prefixed("quasiquote", position)); continue;},
+            Tokens::Unquote => {open.push(Open::Prefixed("unquote", position)); continue;},
+            Tokens::UnquoteSplicing => {open.push(Open::Prefixed("unquote-splicing", position)); continue;},
+            Tokens::Discard => {open.push(Open::Discard(position)); continue;},
+            Tokens::Comment(_) => continue,
+            Tokens::CP => match open.pop() {
+                Some(Open::List(elems, children, position)) => (Type::List(elems), SourceMap { position, children }),
+                Some(Open::Prefixed(name, position)) => return Err(ParseError::MissingForm(name, position)),
+                Some(Open::Discard(position)) => return Err(ParseError::MissingForm("#_", position)),
+                None => return Err(ParseError::UnexpectedClose(position))
+            }
+        };
+        // completed form goes to the innermost open list, wrapping it in pending prefixes first
+        loop {
+            match open.last_mut() {
+                Some(Open::Prefixed(name, position)) => {
+                    let prefix = SourceMap { position: *position, children: vec![] };
+                    node = Type::List(vec![Type::Symbol(String::from(*name)), node]);
+                    map = SourceMap { position: *position, children: vec![prefix, map] };
+                    open.pop();
+                },
+                Some(Open::Discard(_)) => {
+                    open.pop();
+                    break;
+                },
+                Some(Open::List(elems, children, _)) => {
+                    elems.push(node);
+                    children.push(map);
+                    break;
+                },
+                None => {
+                    forms.push(node);
+                    maps.push(map);
+                    break;
+                }
+            }
         }
-        current_pointer += 1;
     }
-}
\ No newline at end of file
+    match open.pop() {
+        Some(Open::List(_, _, position)) => Err(ParseError::Unclosed(position)),
+        Some(Open::Prefixed(name, position)) => Err(ParseError::MissingFormAtEnd(name, position)),
+        Some(Open::Discard(position)) => Err(ParseError::MissingFormAtEnd("#_", position)),
+        None => Ok((forms, maps))
+    }
+}
@@ -0,0 +1,281 @@
+use std::borrow::Cow;
+
+use crate::lexer::{self, Tokens};
+use crate::lisp::Type;
+use crate::parser::{self, ParseError};
+
+/// Width used by `pprint`, the REPL and `lesp fmt`
+pub const WIDTH: usize = 80;
+
+// what the printer lays out, source text keeps atoms as they were written and keeps comments
+enum Node<'a> {
+    Symbol(Cow<'a, str>),
+    /// number, string, `nil`, `true`, `false` or a function
+    Atom(Cow<'a, str>),
+    List(Vec<Node<'a>>),
+    /// `'`, `` ` ``, `,`, `,@` or `#_` followed by a form
+    Prefixed(&'a str, Box<Node<'a>>),
+    /// comment, trailing when it follows a form on the same line
+    Comment(&'a str, bool),
+    /// empty line between forms
+    Blank
+}
+
+impl<'a> Node<'a> {
+    fn from_value(value: &Type) -> Node<'static> {
+        match value {
+            Type::Symbol(name) => Node::Symbol(Cow::Owned(name.clone())),
+            Type::List(elems) => Node::List(elems.iter().map(Node::from_value).collect()),
+            Type::Located(form, _) => Node::from_value(form),
+            _ => Node::Atom(Cow::Owned(value.to_string()))
+        }
+    }
+
+    // single line text of the node, `None` when it can't be written on one line
+    fn flat(&self) -> Option<String> {
+        match self {
+            Node::Symbol(text) | Node::Atom(text) if !text.contains('\n') => Some(text.to_string()),
+            Node::Prefixed(prefix, form) => form.flat().map(|form| format!("{}{}", prefix, form)),
+            Node::List(elems) => {
+                let elems: Option<Vec<String>> = elems.iter().map(Node::flat).collect();
+                elems.map(|elems| format!("({})", elems.join(" ")))
+            },
+            _ => None
+        }
+    }
+}
+
+fn width(text: &str) -> usize {
+    text.chars().count()
+}
+
+// special forms and macros taking `n` arguments before their body, the body is indented by two
+fn body_args(head: &str) -> Option<usize> {
+    match head {
+        "do" | "try" | "finally" | "comment" => Some(0),
+        "def" | "def-" | "let" | "when" | "catch" | "ns" => Some(1),
+        "defn" | "defn-" | "defmacro" | "defhmacro" | "fn" | "macro" | "hmacro" => Some(2),
+        _ => None
+    }
+}
+
+// `(defn f (a) (g a))` always puts its body on a new line, `(defn id (a) a)` stays on one
+fn is_definition_with_body(node: &Node) -> bool {
+    match node {
+        Node::List(elems) if elems.len() > 3 => {
+            matches!(&elems[0], Node::Symbol(head) if matches!(head.as_ref(), "defn" | "defn-" | "defmacro" | "defhmacro"))
+                && elems[3..].iter().any(|elem| matches!(elem, Node::List(_) | Node::Prefixed(..)))
+        },
+        _ => false
+    }
+}
+
+struct Printer {
+    out: String,
+    width: usize
+}
+
+impl Printer {
+    fn column(&self) -> usize {
+        width(&self.out[self.out.rfind('\n').map_or(0, |i| i + 1)..])
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.out.extend(std::iter::repeat_n(' ', indent));
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        self.column() + width(text) <= self.width
+    }
+
+    fn node(&mut self, node: &Node) {
+        if let Some(flat) = node.flat() {
+            if self.fits(&flat) && !is_definition_with_body(node) {
+                self.out.push_str(&flat);
+                return;
+            }
+        }
+        match node {
+            Node::Symbol(text) | Node::Atom(text) => self.out.push_str(text),
+            Node::Comment(text, _) => self.out.push_str(text),
+            Node::Prefixed(prefix, form) => {
+                self.out.push_str(prefix);
+                self.node(form);
+            },
+            Node::List(elems) => self.list(elems),
+            Node::Blank => ()
+        }
+    }
+
+    /**
+    (defn name (args)     forms with a body keep their arguments on the first line
+      body)
+    (f a                  calls align arguments under the first one
+       b)
+    (1 2 3                lists of atoms are filled up to the width
+     4 5)
+    */
+    fn list(&mut self, elems: &[Node]) {
+        let column = self.column();
+        self.out.push('(');
+        let (inline, indent) = match elems.first() {
+            Some(Node::Symbol(head)) => match body_args(head) {
+                Some(n) => (n + 1, column + 2),
+                None => (2, column + 2 + width(head))
+            },
+            _ => (1, column + 1)
+        };
+        let fill = elems.iter().all(|elem| matches!(elem, Node::Symbol(_) | Node::Atom(_)));
+        if self.forms(elems, inline, indent, fill) {
+            self.newline(indent);
+        }
+        self.out.push(')');
+    }
+
+    // the first `inline` forms share a line, returns true when the last form is a comment
+    fn forms(&mut self, forms: &[Node], inline: usize, indent: usize, fill: bool) -> bool {
+        let inline = forms.iter().position(|form| matches!(form, Node::Comment(..) | Node::Blank)).map_or(inline, |i| i.min(inline));
+        let mut after_comment = false;
+        for (i, form) in forms.iter().enumerate() {
+            match form {
+                Node::Blank => {
+                    self.out.push('\n');
+                    continue;
+                },
+                Node::Comment(_, true) if i > 0 && !after_comment => self.out.push(' '),
+                _ if i == 0 => (),
+                _ if after_comment => self.newline(indent),
+                _ if i < inline => self.out.push(' '),
+                _ if fill && form.flat().is_some_and(|flat| self.fits(&format!(" {}", flat))) => self.out.push(' '),
+                _ => self.newline(indent)
+            }
+            self.node(form);
+            after_comment = matches!(form, Node::Comment(..));
+        }
+        after_comment
+    }
+}
+
+/// Lays out `value` within `width` columns, it's printed on one line when it fits
+pub fn pretty(value: &Type, width: usize) -> String {
+    let mut printer = Printer { out: String::new(), width };
+    printer.node(&Node::from_value(value));
+    printer.out
+}
+
+enum Open<'a> {
+    List(Vec<Node<'a>>),
+    Prefixed(&'a str)
+}
+
+// forms of the source with comments and empty lines between them
+fn source_nodes(input: &str) -> Result<Vec<Node<'_>>, ParseError> {
+    // the reader reports malformed input, after it the source is known to be balanced
+    parser::read(input)?;
+    let mut open = vec![Open::List(vec![])];
+    let mut last_line = 0;
+    for (token, position, text) in lexer::parse_fsm_with_comments(input)? {
+        let after_form = match open.last() {
+            Some(Open::List(elems)) => !elems.is_empty(),
+            _ => false
+        };
+        let same_line = after_form && position.line == last_line;
+        if after_form && position.line > last_line + 1 && token != Tokens::CP {
+            if let Some(Open::List(elems)) = open.last_mut() {
+                elems.push(Node::Blank);
+            }
+        }
+        last_line = position.line + text.matches('\n').count();
+        let mut node = match token {
+            Tokens::OP => {
+                open.push(Open::List(vec![]));
+                continue;
+            },
+            Tokens::Quote | Tokens::Quasiquote | Tokens::Unquote | Tokens::UnquoteSplicing | Tokens::Discard => {
+                open.push(Open::Prefixed(text));
+                continue;
+            },
+            Tokens::Comment(comment) => {
+                // a comment between a prefix and its form goes before both
+                let elems = open.iter_mut().rev().find_map(|open| match open {
+                    Open::List(elems) => Some(elems),
+                    Open::Prefixed(_) => None
+                });
+                if let Some(elems) = elems {
+                    elems.push(Node::Comment(comment, same_line));
+                }
+                continue;
+            },
+            Tokens::CP => match open.pop() {
+                Some(Open::List(elems)) => Node::List(elems),
+                _ => unreachable!("checked by the reader")
+            },
+            Tokens::Symbol(_) => Node::Symbol(Cow::Borrowed(text)),
+            Tokens::Number(_) | Tokens::Str(_) => Node::Atom(Cow::Borrowed(text))
+        };
+        while let Some(Open::Prefixed(prefix)) = open.last() {
+            node = Node::Prefixed(prefix, Box::new(node));
+            open.pop();
+        }
+        if let Some(Open::List(elems)) = open.last_mut() {
+            elems.push(node);
+        }
+    }
+    match open.pop() {
+        Some(Open::List(forms)) => Ok(forms),
+        _ => unreachable!("checked by the reader")
+    }
+}
+
+/// Reformats source code, comments and single empty lines between forms are kept
+pub fn format_source(input: &str, width: usize) -> Result<String, ParseError> {
+    let forms = source_nodes(input)?;
+    let mut printer = Printer { out: String::new(), width };
+    printer.forms(&forms, 0, 0, false);
+    if !printer.out.is_empty() {
+        printer.out.push('\n');
+    }
+    Ok(printer.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(input: &str) -> String {
+        format_source(input, 40).unwrap()
+    }
+
+    #[test]
+    fn test_pretty() {
+        let read = |s: &str| parser::read(s).unwrap().0.remove(0);
+        assert_eq!(pretty(&read("(1 \"a\" (b c))"), 40), "(1 \"a\" (b c))");
+        assert_eq!(pretty(&read("(10 20 30 40 50 60 70 80 90)"), 12), "(10 20 30 40\n 50 60 70 80\n 90)");
+        assert_eq!(pretty(&read("(if (> a 100) (list a a a) (list b b b))"), 20),
+                   "(if (> a 100)\n    (list a a a)\n    (list b b b))");
+        assert_eq!(pretty(&read("(let ((a 1) (b 2)) (+ a b))"), 16), "(let ((a 1)\n      (b 2))\n  (+ a b))");
+    }
+
+    #[test]
+    fn test_format_source() {
+        assert_eq!(format("  (defn  f (a)\n(+ a\n 1))   ;; trailing\n\n\n\n(def x '( 1 2 ))"),
+                   "(defn f (a)\n  (+ a 1)) ;; trailing\n\n(def x '(1 2))\n");
+        assert_eq!(format("#!/usr/bin/env lesp\n(a ; one\n ;; two\n #| three |# b #_ c)\n;; end"),
+                   "#!/usr/bin/env lesp\n(a ; one\n   ;; two\n   #| three |#\n   b\n   #_c)\n;; end\n");
+        assert_eq!(format("(list 0xff \"a\\tb\" 1_000)"), "(list 0xff \"a\\tb\" 1_000)\n");
+        assert_eq!(format(""), "");
+        assert_eq!(format_source("(a", 40), Err(ParseError::Unclosed(crate::lexer::Position { line: 1, col: 1 })));
+    }
+
+    #[test]
+    fn test_format_is_idempotent() {
+        let prelude = String::from_utf8_lossy(include_bytes!("../res/init.lisp"));
+        for width in [20, 40, 80] {
+            let once = format_source(&prelude, width).unwrap();
+            assert_eq!(format_source(&once, width).unwrap(), once);
+            assert_eq!(parser::read(&once).unwrap().0, parser::read(&prelude).unwrap().0);
+        }
+        assert_eq!(format_source(&prelude, WIDTH).unwrap(), prelude);
+    }
+}
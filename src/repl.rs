@@ -0,0 +1,251 @@
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper};
+
+use lesp::{Error, Interpreter, Value};
+
+const HELP: &str = "\
+:env          list bindings
+:doc name     describe a binding
+:load file    evaluate a file
+:time expr    evaluate and print how long it took
+:reset        start over with a fresh context
+:save file    write inputs of this session to a file
+:quit         exit";
+
+/// Completes names bound in the context, highlights matching parens and keeps
+/// reading lines until the input is complete
+struct LispHelper {
+    names: Vec<String>,
+    brackets: MatchingBracketHighlighter
+}
+
+impl LispHelper {
+    fn new(lisp: &Interpreter) -> LispHelper {
+        LispHelper { names: lisp.names(), brackets: MatchingBracketHighlighter::new() }
+    }
+}
+
+impl Completer for LispHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = lesp::symbol_start(&line[..pos]);
+        let prefix = &line[start..pos];
+        let candidates = self.names.iter().filter(|name| name.starts_with(prefix)).cloned().collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for LispHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LispHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.brackets.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.brackets.highlight_char(line, pos, kind)
+    }
+}
+
+impl Validator for LispHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(if lesp::is_incomplete(ctx.input()) {
+            ValidationResult::Incomplete
+        } else {
+            ValidationResult::Valid(None)
+        })
+    }
+}
+
+impl Helper for LispHelper {}
+
+// `~/.lesp_history`, history isn't kept when there is no home directory
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".lesp_history"))
+}
+
+/// Line editor with history when attached to a terminal, plain line reading otherwise,
+/// `:reset` replaces `lisp` with what `init` returns
+pub fn run(lisp: &mut Interpreter, init: &dyn Fn() -> Interpreter) {
+    if io::stdin().is_terminal() {
+        run_editor(lisp, init);
+    } else {
+        run_lines(lisp, init);
+    }
+}
+
+fn run_editor(lisp: &mut Interpreter, init: &dyn Fn() -> Interpreter) {
+    let mut editor: Editor<LispHelper, DefaultHistory> = Editor::new().expect("Cannot initialize line editor");
+    editor.set_helper(Some(LispHelper::new(lisp)));
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history on the first run
+        let _ = editor.load_history(path);
+    }
+    let mut inputs = vec![];
+    loop {
+        match editor.readline(">> ") {
+            Ok(input) => {
+                let _ = editor.add_history_entry(input.as_str());
+                if !eval_input(&input, lisp, init, &mut inputs) {
+                    break;
+                }
+                if let Some(helper) = editor.helper_mut() {
+                    helper.names = lisp.names();
+                }
+            },
+            Err(ReadlineError::Interrupted) => (),
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("!! {}", e);
+                break;
+            }
+        }
+    }
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            println!("!! Cannot save history -> {}", e);
+        }
+    }
+}
+
+fn run_lines(lisp: &mut Interpreter, init: &dyn Fn() -> Interpreter) {
+    let stdin = io::stdin();
+    print(">> ");
+
+    // lines are collected until they form complete input
+    let mut inputs = vec![];
+    let mut input = String::new();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                println!("!! {}", e);
+                return;
+            }
+        };
+        input.push_str(&line);
+        input.push('\n');
+        if lesp::is_incomplete(&input) {
+            print(".. ");
+            continue;
+        }
+        if !eval_input(&input, lisp, init, &mut inputs) {
+            return;
+        }
+        input.clear();
+        print(">> ");
+    }
+    if !input.is_empty() {
+        eval_input(&input, lisp, init, &mut inputs);
+    }
+}
+
+// evaluates lisp input or runs a `:command`, returns false when the session should end
+fn eval_input(input: &str, lisp: &mut Interpreter, init: &dyn Fn() -> Interpreter, inputs: &mut Vec<String>) -> bool {
+    let trimmed = input.trim();
+    if !trimmed.starts_with(':') {
+        inputs.push(String::from(input.trim_end()));
+        print_result(lisp.eval_source(input, "<stdin>"));
+        return true;
+    }
+    let (command, arg) = match trimmed.find(char::is_whitespace) {
+        Some(i) => (&trimmed[..i], trimmed[i..].trim()),
+        None => (trimmed, "")
+    };
+    match command {
+        ":quit" => return false,
+        ":help" => println!("{}", HELP),
+        ":env" => for name in lisp.names() {
+            if let Some(value) = lisp.get(&name) {
+                println!("{} {}", name, describe(&value));
+            }
+        },
+        ":doc" | ":load" | ":time" | ":save" if arg.is_empty() => println!("!! {} expects an argument", command),
+        ":doc" => match lisp.get(arg) {
+            Some(value) => {
+                println!("{} {}", arg, describe(&value));
+                match value {
+                    Value::Function(f) if !f.doc().is_empty() => println!("  {}", f.doc()),
+                    _ => ()
+                }
+            },
+            None => println!("!! Symbol not found -> {:?}", arg)
+        },
+        ":load" => print_result(lisp.eval_file(arg)),
+        ":time" => {
+            let start = Instant::now();
+            let result = lisp.eval_source(arg, "<stdin>");
+            println!(";; {:?}", start.elapsed());
+            print_result(result);
+        },
+        ":reset" => {
+            *lisp = init();
+            println!(";; context reset");
+        },
+        ":save" => match fs::write(arg, inputs.join("\n") + "\n") {
+            Ok(()) => println!(";; saved {} inputs to {}", inputs.len(), arg),
+            Err(e) => println!("!! Cannot write {} -> {}", arg, e)
+        },
+        _ => println!("!! Unknown command {}, :help lists commands", command)
+    }
+    true
+}
+
+fn print_result(result: Result<Value, Error>) {
+    match result {
+        // continuation lines are indented past the prompt
+        Ok(r) => println!("<< {}", r.pretty(lesp::WIDTH - 3).replace('\n', "\n   ")),
+        Err(e) => println!("!! {}", e)
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Function(f) => f.signature(),
+        other => other.to_string()
+    }
+}
+
+fn print(s: &str) {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    handle.write_all(s.as_bytes()).expect("Cannot write to stdout");
+    handle.flush().expect("Cannot write to stdout");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset() {
+        let init = || {
+            let mut lisp = Interpreter::new();
+            lisp.set("*argv*", Value::List(vec![Value::Str(String::from("a"))]));
+            lisp
+        };
+        let mut lisp = init();
+        let mut inputs = vec![];
+        assert!(eval_input("(def x 1)", &mut lisp, &init, &mut inputs));
+        assert!(eval_input(":reset", &mut lisp, &init, &mut inputs));
+        assert_eq!(lisp.get("x"), None);
+        assert_eq!(lisp.get("defn"), None);
+        assert_eq!(lisp.get("*argv*").map(|argv| argv.to_string()), Some(String::from("(\"a\")")));
+    }
+}